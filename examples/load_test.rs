@@ -0,0 +1,237 @@
+//! Compares the blocking server with the event loop under load.
+//!
+//! A few slow clients trickle in never-ending requests, the way a bad network
+//! or a slowloris attack would, while the rest fetch a page over keep-alive
+//! connections as fast as they can.
+//!
+//! ```text
+//! cargo run --release --features event-loop --example load_test -- --slow-clients 8
+//! ```
+
+use std::{
+    env, fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use blog::{
+    config::Config,
+    server::{
+        access_log::{AccessLog, LogFormat},
+        Server,
+    },
+};
+use clap::Parser;
+
+#[derive(Parser)]
+struct Args {
+    /// Clients fetching the page
+    #[arg(long, default_value = "16")]
+    clients: usize,
+    /// Seconds each mode is loaded for
+    #[arg(long, default_value = "10")]
+    seconds: u64,
+    /// Clients that start a request and never finish it
+    #[arg(long, default_value = "8")]
+    slow_clients: usize,
+    /// Worker threads in both modes
+    #[arg(long, default_value = "4")]
+    workers: u32,
+}
+
+// What the fast clients saw
+#[derive(Default)]
+struct Report {
+    ok: usize,
+    rejected: usize,
+    failed: usize,
+    latencies: Vec<Duration>,
+    elapsed: Duration,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+
+    let site = env::temp_dir().join(format!("blog-load-test-{}", std::process::id()));
+    fs::create_dir_all(&site)?;
+    fs::write(site.join("index.html"), "<p>Hello</p>\n".repeat(512))?;
+
+    println!(
+        "{} clients for {}s, {} slow clients, {} workers\n",
+        args.clients, args.seconds, args.slow_clients, args.workers
+    );
+    println!("{:<12} {:>8} {:>8} {:>8} {:>10} {:>10} {:>10}", "mode", "ok", "503", "failed", "req/s", "p50", "p99");
+
+    for event_loop in [false, true] {
+        let report = run(&args, &site, event_loop)?;
+        let mode = if event_loop { "event loop" } else { "blocking" };
+        println!(
+            "{:<12} {:>8} {:>8} {:>8} {:>10.0} {:>10} {:>10}",
+            mode,
+            report.ok,
+            report.rejected,
+            report.failed,
+            report.ok as f64 / report.elapsed.as_secs_f64(),
+            format!("{:.1?}", percentile(&report.latencies, 0.50)),
+            format!("{:.1?}", percentile(&report.latencies, 0.99)),
+        );
+    }
+
+    fs::remove_dir_all(&site)?;
+    Ok(())
+}
+
+fn run(args: &Args, site: &Path, event_loop: bool) -> Result<Report, Box<dyn std::error::Error>> {
+    let access_log = AccessLog::file(&site.join("access.log"), LogFormat::Combined)?;
+    let server = Server::new(Config::default())
+        .with_port(0)
+        .with_root(site)
+        .with_workers(args.workers)
+        .with_access_log(access_log)
+        .with_event_loop(event_loop)
+        .start()?;
+    let addr = server.local_addr();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let slow: Vec<_> = (0..args.slow_clients)
+        .map(|_| {
+            let done = Arc::clone(&done);
+            thread::spawn(move || slow_client(addr, &done))
+        })
+        .collect();
+    // Let the slow clients get their requests started first
+    thread::sleep(Duration::from_millis(200));
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(args.seconds);
+    let clients: Vec<_> = (0..args.clients)
+        .map(|_| thread::spawn(move || fast_client(addr, deadline)))
+        .collect();
+
+    let mut report = Report::default();
+    for client in clients {
+        let client = client.join().map_err(|_| "client panicked")?;
+        report.ok += client.ok;
+        report.rejected += client.rejected;
+        report.failed += client.failed;
+        report.latencies.extend(client.latencies);
+    }
+    report.elapsed = start.elapsed();
+
+    done.store(true, Ordering::SeqCst);
+    for client in slow {
+        let _ = client.join();
+    }
+    server.shutdown();
+    Ok(report)
+}
+
+// Sends one header line a second, never the blank line ending the request
+fn slow_client(addr: SocketAddr, done: &AtomicBool) {
+    let Ok(mut stream) = TcpStream::connect(addr) else { return };
+    if stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n").is_err() {
+        return;
+    }
+    while !done.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_secs(1));
+        if stream.write_all(b"X-Slow: 1\r\n").is_err() {
+            // Timed out by the server, start over like a real attacker would
+            let Ok(reconnected) = TcpStream::connect(addr) else { return };
+            stream = reconnected;
+            let _ = stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n");
+        }
+    }
+}
+
+fn fast_client(addr: SocketAddr, deadline: Instant) -> Report {
+    let mut report = Report::default();
+    let mut connection = None;
+
+    while Instant::now() < deadline {
+        let start = Instant::now();
+        let reader = match connection.take() {
+            Some(reader) => Ok(reader),
+            None => connect(addr),
+        };
+        let result = reader.and_then(|mut reader| get(&mut reader).map(|(status, keep_alive)| (reader, status, keep_alive)));
+
+        match result {
+            Ok((reader, status, keep_alive)) => {
+                match status {
+                    200 => {
+                        report.ok += 1;
+                        report.latencies.push(start.elapsed());
+                    }
+                    503 => report.rejected += 1,
+                    _ => report.failed += 1,
+                }
+                if keep_alive {
+                    connection = Some(reader);
+                }
+            }
+            Err(_) => report.failed += 1,
+        }
+    }
+    report
+}
+
+fn connect(addr: SocketAddr) -> io::Result<BufReader<TcpStream>> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    Ok(BufReader::new(stream))
+}
+
+// GET / on an open connection, returning the status and whether the server
+// keeps the connection open
+fn get(reader: &mut BufReader<TcpStream>) -> io::Result<(u16, bool)> {
+    reader.get_mut().write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")?;
+
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let status = line
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad status line"))?;
+
+    let mut content_length = 0;
+    let mut keep_alive = false;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            if name.eq_ignore_ascii_case("Content-Length") {
+                content_length = value.parse().unwrap_or(0);
+            } else if name.eq_ignore_ascii_case("Connection") {
+                keep_alive = value.eq_ignore_ascii_case("keep-alive");
+            }
+        }
+    }
+
+    io::copy(&mut reader.by_ref().take(content_length), &mut io::sink())?;
+    Ok((status, keep_alive))
+}
+
+fn percentile(latencies: &[Duration], fraction: f64) -> Duration {
+    if latencies.is_empty() {
+        return Duration::ZERO;
+    }
+    let mut sorted = latencies.to_vec();
+    sorted.sort_unstable();
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index]
+}
@@ -0,0 +1,36 @@
+#![no_main]
+
+// Run with `cargo +nightly fuzz run request_parser` from the repository root
+
+use blog::server::request::{parse_request, ParseError, MAX_HEADERS, MAX_HEADER_BYTES};
+use libfuzzer_sys::fuzz_target;
+use std::io::BufReader;
+
+fuzz_target!(|data: &[u8]| {
+    // A tiny buffer forces lines to be assembled across many fill_buf calls
+    let mut reader = BufReader::with_capacity(7, data);
+
+    // Keep parsing pipelined requests until the input is exhausted or rejected,
+    // the parser must never panic and must respect its limits
+    loop {
+        match parse_request(&mut reader) {
+            Ok(request) => {
+                assert!(request.headers.len() <= MAX_HEADERS);
+                let header_bytes: usize = request
+                    .headers
+                    .iter()
+                    .map(|(name, value)| name.len() + value.len())
+                    .sum();
+                assert!(header_bytes <= MAX_HEADER_BYTES);
+                // Nothing that could split a header we echo back gets through
+                assert!(!request.path.bytes().any(|byte| byte.is_ascii_control()));
+                assert!(!request.headers.values().any(|value| value.bytes().any(|byte| byte.is_ascii_control() && byte != b'\t')));
+            }
+            Err(ParseError::Closed) => break,
+            Err(err) => {
+                assert!(err.status().is_some() || matches!(err, ParseError::Io(_)));
+                break;
+            }
+        }
+    }
+});
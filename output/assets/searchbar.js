@@ -87,7 +87,7 @@ function renderResults(last_results, new_results) {
             postElement.className = "result-item";
             postElement.dataset.id = postToAdd.id; // Store the ID for easy reference
             postElement.innerHTML = `
-                <h3>${postToAdd.title}</h3>
+                <h3><a href="${postToAdd.url}">${postToAdd.title}</a></h3>
                 <p>${postToAdd.description}</p>
                 <p class="tags">Tags: ${postToAdd.tags.join(", ")}</p>
             `;
@@ -0,0 +1,134 @@
+use std::{collections::{BTreeMap, HashMap}, fs, path::Path};
+use serde::Deserialize;
+
+// Optional site configuration, read from the directory blog is run in
+pub const CONFIG_FILE: &str = "blog.json";
+
+/// Site configuration loaded from `blog.json`.
+///
+/// Every section and field is optional, a missing file means all defaults.
+///
+/// # Examples
+/// ```json
+/// {
+///     "pretty_urls": true,
+///     "headers": {
+///         "/*": { "X-Frame-Options": "DENY" },
+///         "/assets/*": { "Cache-Control": "public, max-age=3600" }
+///     },
+///     "redirects": [
+///         { "from": "/old-post", "to": "/new-post/" },
+///         { "from": "/blog/*", "to": "/:splat", "status": 302 }
+///     ],
+///     "server": {
+///         "mime_types": { "wasm": "application/wasm" },
+///         "queue_capacity": 64,
+///         "min_workers": 2,
+///         "max_workers": 16,
+///         "status_endpoint": true,
+///         "file_cache_mb": 64,
+///         "directory_listing": true
+///     }
+/// }
+/// ```
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Write pages as `slug/index.html` served at `/slug/` instead of `/slug.html`
+    pub pretty_urls: bool,
+    /// URL path glob => headers added to matching responses, `*` matches anything
+    pub headers: BTreeMap<String, BTreeMap<String, String>>,
+    /// Checked in order, the first rule matching a path wins
+    pub redirects: Vec<Redirect>,
+    pub server: ServerConfig,
+}
+
+/// A redirect rule, with the same meaning as a line of a Netlify `_redirects` file.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Redirect {
+    /// Path to redirect, a trailing `*` matches the rest of the path
+    pub from: String,
+    /// Where to send the client, `:splat` is replaced with what `*` matched
+    pub to: String,
+    /// 301 or 302
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+    /// Redirect even when a file exists at `from`
+    #[serde(default)]
+    pub force: bool,
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Extension => MIME type, taking precedence over the built-in table
+    pub mime_types: HashMap<String, String>,
+    /// Connections that may wait for a free worker before getting a 503
+    pub queue_capacity: Option<usize>,
+    /// Threads handling connections, one per CPU by default
+    pub workers: Option<u32>,
+    /// Threads kept running when idle, `workers` by default
+    pub min_workers: Option<u32>,
+    /// Threads started under load, `workers` by default
+    pub max_workers: Option<u32>,
+    /// Answer `/__blog/status` with server and build status as JSON
+    pub status_endpoint: bool,
+    /// Keep up to this many megabytes of served files in memory
+    pub file_cache_mb: Option<u64>,
+    /// List directories without an `index.html` instead of answering 404
+    pub directory_listing: bool,
+}
+
+impl Config {
+    pub fn load(dir: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+        let config_path = dir.join(CONFIG_FILE);
+        if !config_path.exists() {
+            return Ok(Config::default());
+        }
+
+        let contents = fs::read_to_string(&config_path)?;
+        let config: Config = serde_json::from_str(&contents)
+            .map_err(|err| format!("Invalid {}: {}", config_path.display(), err))?;
+        config
+            .validate()
+            .map_err(|err| format!("Invalid {}: {}", config_path.display(), err))?;
+        Ok(config)
+    }
+
+    // Catch rules that would produce broken responses or export files
+    fn validate(&self) -> Result<(), String> {
+        for (glob, headers) in &self.headers {
+            if !glob.starts_with('/') || glob.contains(char::is_whitespace) {
+                return Err(format!("header path {glob:?} must start with / and have no spaces"));
+            }
+            for (name, value) in headers {
+                let valid_name = !name.is_empty()
+                    && name.bytes().all(|byte| byte.is_ascii_graphic() && byte != b':');
+                if !valid_name {
+                    return Err(format!("invalid header name {name:?} for {glob}"));
+                }
+                if value.chars().any(char::is_control) {
+                    return Err(format!("invalid value for header {name} for {glob}"));
+                }
+            }
+        }
+
+        for redirect in &self.redirects {
+            if !redirect.from.starts_with('/') || redirect.from.contains(char::is_whitespace) {
+                return Err(format!("redirect from {:?} must start with / and have no spaces", redirect.from));
+            }
+            if redirect.to.is_empty() || redirect.to.chars().any(|c| c.is_whitespace() || c.is_control()) {
+                return Err(format!("invalid redirect target {:?}", redirect.to));
+            }
+            if !matches!(redirect.status, 301 | 302) {
+                return Err(format!("redirect status for {} must be 301 or 302", redirect.from));
+            }
+        }
+        Ok(())
+    }
+}
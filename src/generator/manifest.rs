@@ -0,0 +1,246 @@
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+use serde::{Serialize, Deserialize};
+
+// Name of the manifest file, stored at the root of the output directory
+pub const MANIFEST_FILE: &str = ".blog-manifest.json";
+
+/// Record of every file the generator wrote into the output directory.
+///
+/// Paths are stored relative to the output directory using `/` separators,
+/// so hand-written files such as `assets/styles.css` or `404.html` are never
+/// listed and never touched by orphan removal or `blog clean`.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Manifest {
+    files: BTreeSet<String>,
+}
+
+impl Manifest {
+    /// Load the manifest from `output_dir`.
+    ///
+    /// A missing or unreadable manifest yields an empty one, which means
+    /// nothing is considered generated and nothing will be deleted.
+    pub fn load(output_dir: &Path) -> Manifest {
+        let manifest_path = output_dir.join(MANIFEST_FILE);
+        let contents = match fs::read_to_string(&manifest_path) {
+            Ok(contents) => contents,
+            Err(_) => return Manifest::default(),
+        };
+
+        match serde_json::from_str::<Manifest>(&contents) {
+            Ok(mut manifest) => {
+                // Drop anything that could point outside of the output directory
+                manifest.files.retain(|file| is_safe_relative(file));
+                manifest
+            }
+            Err(err) => {
+                println!("Ignoring corrupt manifest {}: {}", manifest_path.display(), err);
+                Manifest::default()
+            }
+        }
+    }
+
+    pub fn save(&self, output_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+        let contents = serde_json::to_string_pretty(self)?;
+        fs::write(output_dir.join(MANIFEST_FILE), contents)?;
+        Ok(())
+    }
+
+    /// Record a generated file, given its path relative to the output directory.
+    pub fn record(&mut self, relative_path: &Path) {
+        let entry = entry(relative_path);
+        if is_safe_relative(&entry) {
+            self.files.insert(entry);
+        }
+    }
+
+    /// Carry the file at `relative_path` over from `previous`, along with
+    /// siblings such as `page.html.gz`, when this build couldn't regenerate it.
+    pub fn keep(&mut self, previous: &Manifest, relative_path: &Path) {
+        let entry = entry(relative_path);
+        let sibling_prefix = format!("{entry}.");
+        let kept = previous
+            .files
+            .iter()
+            .filter(|file| **file == entry || file.starts_with(&sibling_prefix));
+        self.files.extend(kept.cloned());
+    }
+
+    /// Whether the file at `relative_path` is recorded as generated.
+    pub fn contains(&self, relative_path: &Path) -> bool {
+        self.files.contains(&entry(relative_path))
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &String> {
+        self.files.iter()
+    }
+
+    /// Delete every file listed in `previous` that this manifest no longer lists.
+    ///
+    /// Returns the paths that were removed.
+    pub fn remove_orphans(&self, previous: &Manifest, output_dir: &Path) -> Vec<PathBuf> {
+        let orphans = previous.files.difference(&self.files);
+        remove_files(orphans, output_dir)
+    }
+}
+
+/// Remove every generated file recorded in the manifest of `output_dir`,
+/// along with the manifest itself.
+///
+/// Returns the number of files removed.
+pub fn clean(output_dir: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let manifest = Manifest::load(output_dir);
+    let removed = remove_files(manifest.files(), output_dir);
+
+    let manifest_path = output_dir.join(MANIFEST_FILE);
+    if manifest_path.exists() {
+        fs::remove_file(&manifest_path)?;
+    }
+
+    Ok(removed.len())
+}
+
+// "posts/a.html", whatever the platform's separator
+fn entry(relative_path: &Path) -> String {
+    relative_path
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn remove_files<'a>(files: impl Iterator<Item = &'a String>, output_dir: &Path) -> Vec<PathBuf> {
+    let mut removed = Vec::new();
+
+    for file in files {
+        let path = output_dir.join(file);
+        match fs::remove_file(&path) {
+            Ok(_) => {
+                println!("Removed {}", path.display());
+                remove_empty_parents(&path, output_dir);
+                removed.push(path);
+            }
+            // Already gone, nothing to do
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => println!("Failed to remove {}: {}", path.display(), err),
+        }
+    }
+
+    removed
+}
+
+// Remove directories left empty by a deletion, stopping at the output directory
+fn remove_empty_parents(path: &Path, output_dir: &Path) {
+    let mut dir = path.parent();
+    while let Some(current) = dir {
+        if current == output_dir || !current.starts_with(output_dir) {
+            break;
+        }
+        // remove_dir fails on non-empty directories, which is exactly when we stop
+        if fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+}
+
+// Only plain relative paths are allowed in the manifest: no roots, no `..`
+fn is_safe_relative(path: &str) -> bool {
+    !path.is_empty()
+        && path != MANIFEST_FILE
+        && Path::new(path)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output_dir(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("blog-manifest-test-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn write(root: &Path, manifest: &mut Manifest, file: &str) {
+        let path = root.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, file).unwrap();
+        manifest.record(Path::new(file));
+    }
+
+    #[test]
+    fn a_renamed_post_leaves_no_orphans() {
+        let root = output_dir("rename");
+        let mut previous = Manifest::default();
+        write(&root, &mut previous, "old/index.html");
+        write(&root, &mut previous, "old/index.html.gz");
+
+        let mut manifest = Manifest::default();
+        write(&root, &mut manifest, "new/index.html");
+        let removed = manifest.remove_orphans(&previous, &root);
+
+        assert_eq!(removed.len(), 2);
+        assert!(!root.join("old").exists(), "empty directory left behind");
+        assert!(root.join("new/index.html").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn a_deleted_post_leaves_hand_written_files_alone() {
+        let root = output_dir("delete");
+        fs::write(root.join("404.html"), "not found").unwrap();
+        let mut previous = Manifest::default();
+        write(&root, &mut previous, "index.html");
+        write(&root, &mut previous, "gone.html");
+
+        let mut manifest = Manifest::default();
+        write(&root, &mut manifest, "index.html");
+        manifest.remove_orphans(&previous, &root);
+
+        assert!(!root.join("gone.html").exists());
+        assert!(root.join("index.html").exists());
+        assert!(root.join("404.html").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn a_post_that_fails_to_parse_keeps_its_outputs() {
+        let root = output_dir("parse-failure");
+        let mut previous = Manifest::default();
+        write(&root, &mut previous, "broken.html");
+        write(&root, &mut previous, "broken.html.br");
+        write(&root, &mut previous, "broken.htmlx");
+
+        let mut manifest = Manifest::default();
+        manifest.keep(&previous, Path::new("broken.html"));
+        let removed = manifest.remove_orphans(&previous, &root);
+
+        assert_eq!(removed, vec![root.join("broken.htmlx")]);
+        assert!(root.join("broken.html").exists());
+        assert!(root.join("broken.html.br").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn clean_removes_only_generated_files() {
+        let root = output_dir("clean");
+        fs::create_dir_all(root.join("assets")).unwrap();
+        fs::write(root.join("assets/styles.css"), "body{}").unwrap();
+        let mut manifest = Manifest::default();
+        write(&root, &mut manifest, "index.html");
+        write(&root, &mut manifest, "posts/a/index.html");
+        manifest.save(&root).unwrap();
+
+        assert_eq!(clean(&root).unwrap(), 2);
+        assert!(!root.join(MANIFEST_FILE).exists());
+        assert!(!root.join("posts").exists());
+        assert!(root.join("assets/styles.css").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,11 +1,16 @@
+pub mod manifest;
+pub mod netlify;
 pub mod page_builder;
+pub mod precompress;
 
 use std::{
-    fs, io::Write, path::Path,
+    collections::BTreeMap, fs, io::Write, path::{Path, PathBuf},
 };
 use gray_matter::{engine::YAML, Matter, ParsedEntityStruct};
 use serde::{Serialize, Deserialize};
 use std::collections::HashSet;
+use manifest::Manifest;
+use crate::config::{Config, Redirect};
 
 // Create a struct to hold the front matter
 #[derive(Deserialize, Debug)]
@@ -18,21 +23,16 @@ pub struct PostData {
     // keywords: Vec<string>
 }
 
-#[derive(Clone)]
 pub struct File {
     file_name: String,
     stem: String,
-    ext: String,
-    file_data: Vec<u8>,
 }
 
 impl File {
-    fn new(file_name: String, stem: String, ext: String, file_data: Vec<u8>) -> File {
+    fn new(file_name: String, stem: String) -> File {
         File {
             file_name,
             stem,
-            ext,
-            file_data,
         }
     }
 }
@@ -54,10 +54,11 @@ impl Post {
     }
 
     // This constructs a lightweight preview of a post
-    fn to_preview(&self) -> PostPreview {
+    fn to_preview(&self, pretty_urls: bool) -> PostPreview {
         PostPreview {
             id: self.id,
             resource: self.file_data.file_name.clone(),
+            url: page_url(&self.file_data.stem, pretty_urls),
             title: self.parsed_post_data.data.title.clone(),
             tags: self.parsed_post_data.data.tags.clone(),
             date: self.parsed_post_data.data.date.clone(),
@@ -70,32 +71,79 @@ impl Post {
 pub struct PostPreview {
     id: u32,
     resource: String,
+    url: String,
     title: String,
     description: String,
     tags: Vec<String>,
     date: String,
 }
 
-pub fn generate_site(content_dir: &str, output_dir: &str) -> Result<(), Box<dyn std::error::Error>> {
+// Optional steps of a build
+#[derive(Default, Debug, Clone)]
+pub struct BuildOptions {
+    /// Write .gz and .br siblings of compressible outputs for static hosts
+    pub precompress: bool,
+    /// Write pages as `slug/index.html` and link to them as `/slug/`
+    pub pretty_urls: bool,
+    /// Exported as a Netlify `_headers` file
+    pub headers: BTreeMap<String, BTreeMap<String, String>>,
+    /// Exported as a Netlify `_redirects` file
+    pub redirects: Vec<Redirect>,
+}
+
+impl BuildOptions {
+    /// The options that follow from the site configuration, optional steps off.
+    pub fn from_config(config: &Config) -> BuildOptions {
+        BuildOptions {
+            pretty_urls: config.pretty_urls,
+            headers: config.headers.clone(),
+            redirects: config.redirects.clone(),
+            ..BuildOptions::default()
+        }
+    }
+}
+
+/// URL a page is linked to, "index" is always the site root.
+///
+/// With pretty URLs "sample" => "/sample/", otherwise "sample" => "/sample.html".
+pub fn page_url(stem: &str, pretty_urls: bool) -> String {
+    match (stem, pretty_urls) {
+        ("index", _) => "/".to_string(),
+        (stem, true) => format!("/{stem}/"),
+        (stem, false) => format!("/{stem}.html"),
+    }
+}
+
+// Path of a page relative to the output directory, matching `page_url`
+fn page_output_path(stem: &str, pretty_urls: bool) -> PathBuf {
+    match (stem, pretty_urls) {
+        ("index", _) => PathBuf::from("index.html"),
+        (stem, true) => Path::new(stem).join("index.html"),
+        (stem, false) => PathBuf::from(format!("{stem}.html")),
+    }
+}
+
+pub fn generate_site(content_dir: &str, output_dir: &str, options: &BuildOptions) -> Result<(), Box<dyn std::error::Error>> {
 
     // Get Current Directory
     // let current_dir = env::current_dir()?;
 
     // Create Content directory
     if !Path::new(&content_dir).exists() {
-        fs::create_dir(&content_dir)?
+        fs::create_dir(content_dir)?
     }
 
     // Create Output directory
     if !Path::new(&output_dir).exists() {
-        fs::create_dir(&output_dir)?
+        fs::create_dir(output_dir)?
     }
 
     // Create container for read-in files
     let mut posts: Vec<Post> = Vec::new();
     let mut previews: Vec<PostPreview> = Vec::new();
     let mut current_id: u32 = 1;
-    let mut post_files : Vec<File> = Vec::new();
+    // Posts that exist but couldn't be read or parsed this time
+    let mut failed: Vec<File> = Vec::new();
 
     // Get files from directory
     let files = fs::read_dir(content_dir)?;
@@ -138,10 +186,7 @@ pub fn generate_site(content_dir: &str, output_dir: &str) -> Result<(), Box<dyn
 
         // Extract file stem: "sample.md" => "sample"
         let file_stem = match file_path.file_stem() {
-            Some(stem) => match stem.to_str() {
-                Some(valid_unicode) => valid_unicode,
-                None => "Invalid Unicode found in file stem",
-            },
+            Some(stem) => stem.to_str().unwrap_or("Invalid Unicode found in file stem"),
             None => {
                 println!("Empty file stem found");
                 continue;
@@ -153,19 +198,12 @@ pub fn generate_site(content_dir: &str, output_dir: &str) -> Result<(), Box<dyn
             Ok(contents) => contents,
             Err(err) => {
                 println!("Error reading file: {}", err);
+                failed.push(File::new(filename, file_stem.to_string()));
                 continue;
             }
         };
 
-        // If in the future I upload non-markdown types I won't want to turn them
-        // into blog posts, so I push all the files into a post_files collection
-        let new_file = File::new(
-            filename.clone(),
-            file_stem.to_string(),
-            ext,
-            file_contents.clone().into_bytes()
-        );
-        post_files.push(new_file.clone());
+        let new_file = File::new(filename, file_stem.to_string());
 
         // Extract Front Matter from contents of file
         let matter = Matter::<YAML>::new();
@@ -173,13 +211,14 @@ pub fn generate_site(content_dir: &str, output_dir: &str) -> Result<(), Box<dyn
             Some(matter) => matter,
             None => {
                 println!("Something went wrong with the PostData in {display}");
+                failed.push(new_file);
                 continue;
             }
         };
 
         // Add to Post Collection
         let post: Post = Post::new(current_id, new_file, parsed_matter);
-        let preview = post.to_preview();
+        let preview = post.to_preview(options.pretty_urls);
         posts.push(post);
         previews.push(preview);
         // increment id
@@ -194,22 +233,37 @@ pub fn generate_site(content_dir: &str, output_dir: &str) -> Result<(), Box<dyn
         }
     }
 
+    // Track every file written during this build so outputs of deleted or
+    // renamed posts can be cleaned up afterwards
+    let previous_manifest = Manifest::load(Path::new(&output_dir));
+    let mut manifest = Manifest::default();
+
     // Turn blog post => web page
     for post in posts {
         // Generate formatted blog post
         let blog_post = page_builder::generate_blog_post(
             &post,
             &previews,
-            &tag_set
+            &tag_set,
+            options.pretty_urls
         );
         
-        let file_name = post.file_data.stem.clone() + ".html";
+        let file_name = page_output_path(&post.file_data.stem, options.pretty_urls);
         let file_path = Path::new(&output_dir).join(&file_name);
-        
+        manifest.record(&file_name);
+
+        // Pretty URLs put each page in a directory of its own
+        if let Some(parent) = file_path.parent() {
+            if let Err(err) = fs::create_dir_all(parent) {
+                println!("Error creating directory: {}: {}", parent.display(), err);
+                continue;
+            }
+        }
+
         let mut create_file = match fs::File::create(&file_path) {
             Ok(new_file) => new_file,
             Err(err) => {
-                println!("Error creating file: {}: {}", &file_name, err);
+                println!("Error creating file: {}: {}", file_name.display(), err);
                 continue;
             }
         };
@@ -222,5 +276,61 @@ pub fn generate_site(content_dir: &str, output_dir: &str) -> Result<(), Box<dyn
         
     }
 
+    // A post that fails to build, e.g. while it is half edited in `blog watch`,
+    // keeps the page of its last good build rather than losing it as an orphan
+    for file in &failed {
+        manifest.keep(&previous_manifest, &page_output_path(&file.stem, options.pretty_urls));
+    }
+
+    if let Err(err) = netlify::export(&options.headers, &options.redirects, Path::new(&output_dir), &mut manifest, &previous_manifest) {
+        println!("Failed to write _headers and _redirects: {}", err);
+    }
+
+    if options.precompress {
+        match precompress::precompress(Path::new(&output_dir), &mut manifest, &previous_manifest) {
+            Ok(written) => println!("{written} precompressed files written"),
+            Err(err) => println!("Failed to precompress files: {}", err),
+        }
+    }
+
+    // Remove files generated by a previous build that were not produced this time
+    manifest.remove_orphans(&previous_manifest, Path::new(&output_dir));
+    manifest.save(Path::new(&output_dir))?;
+
+    if !failed.is_empty() {
+        let names: Vec<&str> = failed.iter().map(|file| file.file_name.as_str()).collect();
+        return Err(format!("Failed to build {}", names.join(", ")).into());
+    }
+
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POST: &str = "---\nauthor: Zac\ntitle: Sample\ntags: [rust]\ndate: 2024-01-01\ndescription: A sample\n---\nHello";
+
+    #[test]
+    fn a_post_that_fails_to_parse_keeps_its_page() {
+        let root = std::env::temp_dir().join(format!("blog-generator-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let (content_dir, output_dir) = (root.join("content"), root.join("output"));
+        fs::create_dir_all(&content_dir).unwrap();
+        fs::write(content_dir.join("sample.md"), POST).unwrap();
+        let (content, output) = (content_dir.to_str().unwrap(), output_dir.to_str().unwrap());
+        let options = BuildOptions::default();
+
+        generate_site(content, output, &options).unwrap();
+        assert!(output_dir.join("sample.html").exists());
+
+        // Half edited: a required field is misspelled
+        fs::write(content_dir.join("sample.md"), POST.replace("author:", "autor:")).unwrap();
+        assert!(generate_site(content, output, &options).is_err());
+        assert!(output_dir.join("sample.html").exists());
+        // Still recorded, so deleting the post later cleans it up
+        assert!(Manifest::load(&output_dir).contains(Path::new("sample.html")));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+use crate::config::Redirect;
+use super::manifest::Manifest;
+
+pub const HEADERS_FILE: &str = "_headers";
+pub const REDIRECTS_FILE: &str = "_redirects";
+
+/// Write the configured headers and redirects as Netlify `_headers` and
+/// `_redirects` files, so the host applies the same rules as `blog serve`.
+///
+/// Files are only written when there are rules for them, a stale file from
+/// an earlier build is removed with the other orphans. A hand-written file,
+/// one the `previous` build didn't generate, is left alone.
+pub fn export(
+    headers: &BTreeMap<String, BTreeMap<String, String>>,
+    redirects: &[Redirect],
+    output_dir: &Path,
+    manifest: &mut Manifest,
+    previous: &Manifest,
+) -> io::Result<()> {
+    if !headers.is_empty() {
+        write_generated(output_dir, HEADERS_FILE, &headers_file(headers), manifest, previous)?;
+    }
+    if !redirects.is_empty() {
+        write_generated(output_dir, REDIRECTS_FILE, &redirects_file(redirects), manifest, previous)?;
+    }
+    Ok(())
+}
+
+fn write_generated(
+    output_dir: &Path,
+    file_name: &str,
+    contents: &str,
+    manifest: &mut Manifest,
+    previous: &Manifest,
+) -> io::Result<()> {
+    let path = output_dir.join(file_name);
+    if path.exists() && !previous.contains(Path::new(file_name)) {
+        println!("Not overwriting hand-written {}, remove it to have it generated", path.display());
+        return Ok(());
+    }
+
+    fs::write(path, contents)?;
+    manifest.record(Path::new(file_name));
+    Ok(())
+}
+
+// /assets/*
+//   Cache-Control: public, max-age=3600
+fn headers_file(headers: &BTreeMap<String, BTreeMap<String, String>>) -> String {
+    let mut file = String::new();
+    for (glob, headers) in headers {
+        file.push_str(glob);
+        file.push('\n');
+        for (name, value) in headers {
+            file.push_str(&format!("  {name}: {value}\n"));
+        }
+    }
+    file
+}
+
+// /blog/*  /:splat  302
+fn redirects_file(redirects: &[Redirect]) -> String {
+    redirects
+        .iter()
+        .map(|rule| {
+            let force = if rule.force { "!" } else { "" };
+            format!("{}  {}  {}{}\n", rule.from, rule.to, rule.status, force)
+        })
+        .collect()
+}
@@ -4,10 +4,10 @@ use comrak::markdown_to_html;
 use maud::{html, Markup, DOCTYPE};
 use serde_json::json;
 
-use super::{Post, PostPreview};
+use super::{page_url, Post, PostPreview};
 
 
-fn navbar(posts: &[PostPreview], tag_set: &HashSet<String>) -> Markup {
+fn navbar(posts: &[PostPreview], tag_set: &HashSet<String>, pretty_urls: bool) -> Markup {
     let previews = json!(posts).to_string();
 
     html! {
@@ -20,8 +20,8 @@ fn navbar(posts: &[PostPreview], tag_set: &HashSet<String>) -> Markup {
                 ul class="navbar-navlinks" {
                     li class="nav-brand" { a href="/" { "ZG" } }
                     li class="nav-item" { a href="/" { "Home" } }
-                    li class="nav-item" { a href="/archive" { "Archive" } }
-                    li class="nav-item" { a href="/about" { "About" } }
+                    li class="nav-item" { a href=(page_url("archive", pretty_urls)) { "Archive" } }
+                    li class="nav-item" { a href=(page_url("about", pretty_urls)) { "About" } }
                 }
 
                 // Right: Search bar
@@ -35,8 +35,8 @@ fn navbar(posts: &[PostPreview], tag_set: &HashSet<String>) -> Markup {
                             form class="tag-form" {
                                 // Checkboxes for each tag
                                 @for tag in tag_set {
-                                    label class="tag-box" for={ ("checkbox-".to_string() + &tag) } {
-                                        input type="checkbox" id={ ("checkbox-".to_string() + &tag) } name="tags" value={(tag.clone())} {
+                                    label class="tag-box" for={ ("checkbox-".to_string() + tag) } {
+                                        input type="checkbox" id={ ("checkbox-".to_string() + tag) } name="tags" value={(tag.clone())} {
                                         }
                                         span class="tag-term" {
                                             (tag)
@@ -59,14 +59,15 @@ fn navbar(posts: &[PostPreview], tag_set: &HashSet<String>) -> Markup {
                 }
                 // Later potentially add "defer" to this script because it's not
                 // immediately necessary.
-                script src="assets/searchbar.js" {}
+                // Absolute so it resolves from pages nested in directories
+                script src="/assets/searchbar.js" {}
             }
         }
     }
 }
 
 
-fn header(current_post: &Post, posts: &[PostPreview], tag_set: &HashSet<String>) -> Markup {
+fn header(current_post: &Post, posts: &[PostPreview], tag_set: &HashSet<String>, pretty_urls: bool) -> Markup {
     html! {
         // Metadata
         head {
@@ -74,11 +75,11 @@ fn header(current_post: &Post, posts: &[PostPreview], tag_set: &HashSet<String>)
             title { (current_post.parsed_post_data.data.title) }
             meta name="author" content=(current_post.parsed_post_data.data.author );
             meta name="description" content=(current_post.parsed_post_data.data.description);
-            link rel="stylesheet" type="text/css" href="assets/styles.css";
+            link rel="stylesheet" type="text/css" href="/assets/styles.css";
         }
         header {
             // navbar
-            (navbar(posts, tag_set))
+            (navbar(posts, tag_set, pretty_urls))
         }
     }
 }
@@ -106,7 +107,7 @@ fn footer(publishing_date: &str) -> Markup {
 	}
 }
 
-pub fn generate_blog_post(current_post: &Post, previews: &[PostPreview], tag_set: &HashSet<String>) -> Markup {
+pub fn generate_blog_post(current_post: &Post, previews: &[PostPreview], tag_set: &HashSet<String>, pretty_urls: bool) -> Markup {
     // Markdown options and conversion to HTML
     let options = comrak::ComrakOptions::default();
     let content = markdown_to_html(&current_post.parsed_post_data.content, &options);
@@ -116,7 +117,7 @@ pub fn generate_blog_post(current_post: &Post, previews: &[PostPreview], tag_set
     html! {
         (DOCTYPE)
         html {
-            (header(current_post, previews, tag_set))
+            (header(current_post, previews, tag_set, pretty_urls))
             (body(content))
             (footer(publishing_date))
         }
@@ -0,0 +1,118 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+use flate2::{write::GzEncoder, Compression};
+use super::manifest::{Manifest, MANIFEST_FILE};
+
+// Files smaller than this gain next to nothing from compression
+pub const PRECOMPRESS_MIN_SIZE: u64 = 1024;
+
+// Only text formats are worth compressing, images and fonts already are
+const PRECOMPRESS_EXTENSIONS: [&str; 5] = ["html", "css", "js", "xml", "json"];
+
+type Compressor = fn(&[u8]) -> io::Result<Vec<u8>>;
+
+/// Write `.gz` and `.br` siblings next to every compressible file in `output_dir`.
+///
+/// This covers hand-written assets as well as generated pages. Every sibling
+/// is recorded in the manifest, so siblings whose source disappears, or all of
+/// them when a build runs without precompression, are removed as orphans.
+/// Files the `previous` build generated but this one didn't are about to be
+/// removed as orphans themselves, so they are skipped.
+///
+/// Returns the number of sibling files written.
+pub fn precompress(output_dir: &Path, manifest: &mut Manifest, previous: &Manifest) -> io::Result<usize> {
+    let mut written = 0;
+    let mut dirs = vec![output_dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+
+            if file_type.is_dir() {
+                dirs.push(path);
+                continue;
+            }
+
+            let compressible = path
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map(|ext| PRECOMPRESS_EXTENSIONS.contains(&ext))
+                .unwrap_or(false);
+            if !file_type.is_file() || !compressible || entry.file_name() == MANIFEST_FILE {
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            if metadata.len() < PRECOMPRESS_MIN_SIZE {
+                continue;
+            }
+
+            let Ok(relative) = path.strip_prefix(output_dir) else {
+                continue;
+            };
+            if previous.contains(relative) && !manifest.contains(relative) {
+                continue;
+            }
+
+            let siblings: [(&str, Compressor); 2] = [("gz", gzip), ("br", brotli)];
+            for (ext, compress) in siblings {
+                let mut sibling = path.as_os_str().to_owned();
+                sibling.push(".");
+                sibling.push(ext);
+                let sibling = Path::new(&sibling);
+
+                // Skip siblings that are already newer than their source
+                let up_to_date = match (fs::metadata(sibling).and_then(|m| m.modified()), metadata.modified()) {
+                    (Ok(sibling_modified), Ok(modified)) => sibling_modified >= modified,
+                    _ => false,
+                };
+
+                if !up_to_date {
+                    let contents = fs::read(&path)?;
+                    match compress(&contents) {
+                        Ok(compressed) => {
+                            fs::write(sibling, compressed)?;
+                            written += 1;
+                        }
+                        Err(err) if err.kind() == io::ErrorKind::Unsupported => continue,
+                        Err(err) => return Err(err),
+                    }
+                }
+
+                let mut sibling_relative = relative.as_os_str().to_owned();
+                sibling_relative.push(".");
+                sibling_relative.push(ext);
+                manifest.record(Path::new(&sibling_relative));
+            }
+        }
+    }
+
+    Ok(written)
+}
+
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+#[cfg(feature = "brotli")]
+fn brotli(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    {
+        // Build time, so use the slowest and smallest setting
+        let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 11, 22);
+        encoder.write_all(data)?;
+    }
+    Ok(output)
+}
+
+#[cfg(not(feature = "brotli"))]
+fn brotli(_data: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "built without the brotli feature"))
+}
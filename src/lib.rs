@@ -1,26 +1,55 @@
+pub mod config;
 mod generator;
-mod server;
+pub mod server;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 use notify::{RecursiveMode, Watcher};
-use std::{env, error::Error, sync::{mpsc, Arc, Mutex}, thread};
-use generator::generate_site;
-use server::server_create;
+use std::{
+    env, error::Error, net::{IpAddr, Ipv4Addr}, path::PathBuf, sync::mpsc,
+    time::{Duration, Instant, SystemTime},
+};
+use config::{Config, ServerConfig};
+use generator::{generate_site, manifest};
+use server::{
+    access_log::{AccessLog, LogFormat},
+    status::BuildReport,
+    threadpool,
+    tls::TlsConfig,
+    Server, ServerHandle, Shutdown,
+};
+
+pub use generator::BuildOptions;
+
+// Where the generated self-signed certificate is cached, relative to the site
+const TLS_CACHE_DIR: &str = ".blog/tls";
+
+// How often watch mode checks for a shutdown request while waiting for changes
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(200);
 
 /// Define the main CLI structure
 /// 
 /// # Examples
-/// ```
+/// ```text
 /// $ blog help
 /// 
 /// $ blog build
 /// 
+/// $ blog build --precompress
+/// 
 /// $ blog serve
 /// 
 /// $ blog serve 8080
 /// 
+/// $ blog serve --host 0.0.0.0
+/// 
+/// $ blog serve --access-log access.log --log-format json
+/// 
+/// $ blog serve --tls --https-redirect
+/// 
 /// $ blog watch
 /// 
+/// $ blog clean
+/// 
 /// ```
 #[derive(Parser)]
 #[command(name = "blog")]
@@ -34,127 +63,266 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Run the generator, building the html files
-    Build,
-    /// Serve the generated content over a local web server
-    Serve {
-        #[arg(short, long, default_value = "8080")]
-        port: u16,
+    Build {
+        /// Also write .gz and .br versions of compressible files
+        #[arg(long)]
+        precompress: bool,
     },
+    /// Serve the generated content over a local web server
+    Serve(ServerArgs),
     /// Watch the content directory for changes and regenerate files
-    Watch {
-        #[arg(short, long, default_value = "8080")]
-        port: u16,
-    },
+    Watch(ServerArgs),
+    /// Remove every file the generator created in the output directory
+    Clean,
+}
+
+// Options shared by every command that runs the server
+#[derive(Args)]
+struct ServerArgs {
+    #[arg(short, long, default_value = "8080")]
+    port: u16,
+    /// Address to listen on, 0.0.0.0 or :: to expose the server to your network
+    #[arg(long, default_value = "127.0.0.1", value_parser = parse_host)]
+    host: IpAddr,
+    /// Append the access log to this file instead of printing it
+    #[arg(long, value_name = "FILE")]
+    access_log: Option<PathBuf>,
+    /// Access log format, combined or json
+    #[arg(long, default_value_t = LogFormat::Combined)]
+    log_format: LogFormat,
+    /// Serve HTTPS, with a self-signed certificate for localhost unless one is given
+    #[arg(long)]
+    tls: bool,
+    /// PEM certificate chain to use instead of the self-signed one
+    #[arg(long, value_name = "FILE", requires_all = ["tls", "tls_key"])]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long, value_name = "FILE", requires_all = ["tls", "tls_cert"])]
+    tls_key: Option<PathBuf>,
+    /// Redirect plain HTTP requests to HTTPS instead of rejecting them
+    #[arg(long, requires = "tls")]
+    https_redirect: bool,
+    /// Threads handling connections, one per CPU by default
+    #[arg(long, value_name = "N")]
+    workers: Option<u32>,
+    /// Threads kept running when idle, --workers by default
+    #[arg(long, value_name = "N")]
+    min_workers: Option<u32>,
+    /// Threads started under load, --workers by default
+    #[arg(long, value_name = "N")]
+    max_workers: Option<u32>,
+    /// Answer /__blog/status with server and build status as JSON
+    #[arg(long)]
+    status_endpoint: bool,
+    /// Keep up to this many megabytes of served files in memory
+    #[arg(long, value_name = "MB")]
+    file_cache: Option<u64>,
+    /// List directories without an index.html instead of answering 404
+    #[arg(long)]
+    directory_listing: bool,
+    /// Serve connections from a single event loop instead of a worker each
+    #[cfg(feature = "event-loop")]
+    #[arg(long, conflicts_with = "tls")]
+    event_loop: bool,
 }
 
 pub fn run() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
 
     match &cli.command {
-        Commands::Build => {
+        Commands::Build { precompress } => {
+            let config = Config::load(&env::current_dir()?)?;
             println!("Building files");
-            if let Err(err) = build() {
+            let options = BuildOptions {
+                precompress: *precompress,
+                ..BuildOptions::from_config(&config)
+            };
+            if let Err(err) = build(&options) {
                 println!("Error Generating files: {}", err);
             }
         }
-        Commands::Serve { port } => {
-            println!("Starting server on port {port}");
-            serve(*port)?;
+        Commands::Serve(args) => {
+            let config = Config::load(&env::current_dir()?)?;
+            println!("Starting server on port {}", args.port);
+            let server = serve(args, config)?;
+            shutdown_on_signal(&server.shutdown_signal())?;
+            server.wait();
+            println!("Server on port {} stopped", args.port);
         }
-        Commands::Watch { port} => {
+        Commands::Watch(args) => {
+            let config = Config::load(&env::current_dir()?)?;
             println!("Watching for changes...");
-            watch(*port)?;
+            watch(args, config)?;
+        }
+        Commands::Clean => {
+            println!("Cleaning generated files");
+            clean()?;
         }
     }
 
     Ok(())
 }
 
-pub fn build() -> Result<(), Box<dyn Error>> {
+pub fn build(options: &BuildOptions) -> Result<(), Box<dyn Error>> {
     let cd = env::current_dir()?;
     // .to_string_lossy converts unknown unicode into �
     let content_dir = cd.join("content").to_string_lossy().to_string();
     let output_dir = cd.join("output").to_string_lossy().to_string();
-    let _ = match generate_site(&content_dir, &output_dir) {
-        Ok(_) => println!("Generation succeeded! Files built in {}", &output_dir),
-        Err(err) => {
-            println!("Generation failed: {}", err);
-        }
-    };
+    generate_site(&content_dir, &output_dir, options)?;
+    println!("Generation succeeded! Files built in {}", &output_dir);
+    Ok(())
+}
+
+// Removes generated files only, hand-written files in output are left alone
+pub fn clean() -> Result<(), Box<dyn Error>> {
+    let output_dir = env::current_dir()?.join("output");
+    if !output_dir.exists() {
+        println!("Nothing to clean, {} does not exist", output_dir.display());
+        return Ok(());
+    }
+
+    let removed = manifest::clean(&output_dir)?;
+    println!("Removed {removed} generated files from {}", output_dir.display());
     Ok(())
 }
 
+// "localhost" is accepted alongside literal IPv4 and IPv6 addresses
+fn parse_host(host: &str) -> Result<IpAddr, String> {
+    if host.eq_ignore_ascii_case("localhost") {
+        return Ok(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .parse()
+        .map_err(|_| format!("{host} is not an IPv4 or IPv6 address"))
+}
 
-fn serve(port: u16) -> Result<(), Box<dyn Error>> {
-    server_create(port);
+fn serve(args: &ServerArgs, config: Config) -> Result<ServerHandle, Box<dyn Error>> {
+    let output_dir = env::current_dir()?.join("output");
+    let access_log = match &args.access_log {
+        Some(path) => AccessLog::file(path, args.log_format)
+            .map_err(|err| format!("Failed to open access log {}: {}", path.display(), err))?,
+        None => AccessLog::stdout(args.log_format),
+    };
+    let queue_capacity = config.server.queue_capacity;
+    let (min_workers, max_workers) = worker_limits(args, &config.server);
+    let status_endpoint = args.status_endpoint || config.server.status_endpoint;
+    let file_cache_mb = args.file_cache.or(config.server.file_cache_mb);
+    let directory_listing = args.directory_listing || config.server.directory_listing;
+    let mut server = Server::new(config)
+        .with_address(args.host)
+        .with_port(args.port)
+        .with_root(output_dir)
+        .with_access_log(access_log)
+        .with_https_redirect(args.https_redirect)
+        .with_worker_limits(min_workers, max_workers)
+        .with_status_endpoint(status_endpoint)
+        .with_directory_listing(directory_listing);
 
-    println!("Server is running on port {port}");
+    if args.tls {
+        let tls = match (&args.tls_cert, &args.tls_key) {
+            (Some(cert), Some(key)) => TlsConfig::from_pem_files(cert, key)?,
+            _ => {
+                println!("Using a self-signed certificate, your browser will ask you to trust it");
+                TlsConfig::self_signed(&env::current_dir()?.join(TLS_CACHE_DIR))?
+            }
+        };
+        server = server.with_tls(tls);
+    }
+    if let Some(queue_capacity) = queue_capacity {
+        server = server.with_queue_capacity(queue_capacity);
+    }
+    if let Some(file_cache_mb) = file_cache_mb {
+        server = server.with_file_cache(file_cache_mb.saturating_mul(1024 * 1024));
+    }
+    #[cfg(feature = "event-loop")]
+    {
+        server = server.with_event_loop(args.event_loop);
+    }
+    let server = server.start()?;
+
+    println!("Server is running on:");
+    for url in server.urls() {
+        println!("  {url}");
+    }
+    if server.is_exposed() {
+        println!("Warning: the server is reachable from other machines on your network.");
+        println!("Only expose it on networks you trust, it serves everything in output/.");
+    }
+    Ok(server)
+}
+
+// Command line options win over blog.json. Without limits the pool has a
+// fixed size, a single limit only moves that end of the range.
+fn worker_limits(args: &ServerArgs, config: &ServerConfig) -> (u32, u32) {
+    let workers = args.workers.or(config.workers).unwrap_or_else(threadpool::default_size);
+    let max = args.max_workers.or(config.max_workers);
+    let min = args
+        .min_workers
+        .or(config.min_workers)
+        .unwrap_or_else(|| max.map_or(workers, |max| workers.min(max)));
+    (min, max.unwrap_or_else(|| workers.max(min)))
+}
+
+// Triggers `shutdown` on SIGINT (Ctrl-C) or SIGTERM
+fn shutdown_on_signal(shutdown: &Shutdown) -> Result<(), Box<dyn Error>> {
+    let shutdown = shutdown.clone();
+    ctrlc::set_handler(move || {
+        println!("Received shutdown signal");
+        shutdown.trigger();
+    })?;
     Ok(())
 }
 
-fn watch(port: u16) -> Result<(), Box<dyn Error>> {
+fn watch(args: &ServerArgs, config: Config) -> Result<(), Box<dyn Error>> {
     let (tx, rx) = mpsc::channel();
-    let server_handle: Arc<Mutex<Option<thread::JoinHandle<()>>>> = Arc::new(Mutex::new(None));
 
     let mut watcher = notify::recommended_watcher(tx)?;
     
     let cd = env::current_dir()?;
     let content_dir = cd.join("content");
 
-    // Begin serving content in new thread, save handle
-
     watcher.watch(&content_dir, RecursiveMode::Recursive)?;
 
-    {
-        // Start a new server
-        let handle = {
-            let server_handle = Arc::clone(&server_handle);
-            thread::spawn(move || {
-                if let Err(err) = serve(port) {
-                    println!("Error running server: {}", err);
-                }
-                // Ensure the server handle is cleared when the server thread exits
-                *server_handle.lock().unwrap() = None;
-            })
-        };
-        *server_handle.lock().unwrap() = Some(handle);
-    }
+    // Begin serving content in the background. The server reads the output
+    // directory on every request, so it keeps running across rebuilds.
+    let server = serve(args, config.clone())?;
+    let shutdown = server.shutdown_signal();
+    shutdown_on_signal(&shutdown)?;
 
-    loop {
-        match rx.recv() {
+    while !shutdown.is_triggered() {
+        // Wake up regularly to notice a shutdown request
+        match rx.recv_timeout(WATCH_POLL_INTERVAL) {
             Ok(Ok(event)) => {
                 println!("File change detected: {:?}", event);
 
-                match build() {
+                let started = Instant::now();
+                let result = build(&BuildOptions::from_config(&config));
+                // Even a failed build may have rewritten some files
+                server.clear_file_cache();
+                server.report_build(BuildReport {
+                    finished: SystemTime::now(),
+                    duration: started.elapsed(),
+                    error: result.as_ref().err().map(|err| err.to_string()),
+                });
+                match result {
                     Ok(_) => println!("Built Successfully in {}", &content_dir.display()),
                     Err(err) => println!("Error Generating files: {}", err),
                 }
-
-                // Retrieve the server_handle and shut it down
-                let mut guard = server_handle.lock().unwrap();
-                if let Some(handle) = guard.take() {
-                    handle.join().unwrap();
-                }
-
-                // Start a new server
-                let handle = {
-                    let server_handle = Arc::clone(&server_handle);
-                    thread::spawn(move || {
-                        if let Err(err) = serve(port) {
-                            println!("Error running server: {}", err);
-                        }
-                        // Ensure the server handle is cleared when the server thread exits
-                        *server_handle.lock().unwrap() = None;
-                    })
-                };
-                *server_handle.lock().unwrap() = Some(handle);
             }
             Ok(Err(err)) => {
                 println!("Watch error: {:?}", err);
             }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
             Err(err) => {
+                server.shutdown();
                 return Err(format!("Channel receive error: {:?}", err).into());
             }
         }
     }
+
+    // Wait for the server to finish its in-flight requests
+    server.wait();
+    println!("Server on port {} stopped", args.port);
+    Ok(())
 }
@@ -0,0 +1,204 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use super::request::Request;
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// How access log lines are written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    /// Apache/nginx Combined Log Format, followed by the latency in microseconds
+    #[default]
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<LogFormat, String> {
+        match format.to_ascii_lowercase().as_str() {
+            "combined" => Ok(LogFormat::Combined),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format {format}, expected combined or json")),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogFormat::Combined => write!(f, "combined"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// One finished request, as recorded in the access log.
+pub struct Entry<'a> {
+    /// When the request was received
+    pub time: SystemTime,
+    pub peer: SocketAddr,
+    /// None when the request couldn't be parsed
+    pub request: Option<&'a Request>,
+    pub status: u16,
+    /// Body bytes sent, headers are not counted
+    pub bytes: u64,
+    pub latency: Duration,
+}
+
+/// Destination for access log lines, shared by every worker.
+pub struct AccessLog {
+    format: LogFormat,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    /// Log to standard output.
+    pub fn stdout(format: LogFormat) -> AccessLog {
+        AccessLog {
+            format,
+            writer: Mutex::new(Box::new(io::stdout())),
+        }
+    }
+
+    /// Append to the file at `path`, creating it when it doesn't exist.
+    pub fn file(path: &Path, format: LogFormat) -> io::Result<AccessLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AccessLog {
+            format,
+            writer: Mutex::new(Box::new(file)),
+        })
+    }
+
+    pub fn log(&self, entry: &Entry) {
+        let mut line = match self.format {
+            LogFormat::Combined => combined_line(entry),
+            LogFormat::Json => json_line(entry),
+        };
+        line.push('\n');
+
+        // A worker that panicked mid-write leaves at worst a torn line behind
+        let mut writer = self.writer.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Write the line in one go so lines from different workers don't interleave
+        if let Err(err) = writer.write_all(line.as_bytes()).and_then(|_| writer.flush()) {
+            eprintln!("Failed to write access log: {err}");
+        }
+    }
+}
+
+// 127.0.0.1 - - [19/Oct/2026:13:55:36 +0000] "GET / HTTP/1.1" 200 2326 "-" "curl/8.5.0" 412
+fn combined_line(entry: &Entry) -> String {
+    let request_line = match entry.request {
+        Some(request) => format!("{} {} {}", request.method, request.path, request.version),
+        None => "-".to_string(),
+    };
+    let header = |name| {
+        entry
+            .request
+            .and_then(|request| request.header(name))
+            .map(escape)
+            .unwrap_or_else(|| "-".to_string())
+    };
+    // A zero byte body is written as "-" in CLF
+    let bytes = match entry.bytes {
+        0 => "-".to_string(),
+        bytes => bytes.to_string(),
+    };
+
+    format!(
+        "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\" {}",
+        entry.peer.ip(),
+        clf_timestamp(entry.time),
+        escape(&request_line),
+        entry.status,
+        bytes,
+        header("Referer"),
+        header("User-Agent"),
+        entry.latency.as_micros(),
+    )
+}
+
+fn json_line(entry: &Entry) -> String {
+    let request = entry.request;
+    let header = |name| request.and_then(|request| request.header(name));
+    serde_json::json!({
+        "time": rfc3339_timestamp(entry.time),
+        "client": entry.peer.ip().to_string(),
+        "method": request.map(|request| &request.method),
+        "path": request.map(|request| &request.path),
+        "version": request.map(|request| &request.version),
+        "status": entry.status,
+        "bytes": entry.bytes,
+        "referer": header("Referer"),
+        "user_agent": header("User-Agent"),
+        "latency_ms": entry.latency.as_secs_f64() * 1000.0,
+    })
+    .to_string()
+}
+
+// Quotes, backslashes and control characters are escaped like Apache does,
+// so a client can't forge extra fields or lines
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// 19/Oct/2026:13:55:36 +0000
+fn clf_timestamp(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_fields(time);
+    format!(
+        "{day:02}/{}/{year}:{hour:02}:{minute:02}:{second:02} +0000",
+        MONTHS[month as usize - 1]
+    )
+}
+
+// 2026-10-19T13:55:36Z
+fn rfc3339_timestamp(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_fields(time);
+    format!("{year}-{month:02}-{day:02}T{hour:02}:{minute:02}:{second:02}Z")
+}
+
+// Calendar date and time of day in UTC, using Howard Hinnant's days-to-civil
+// algorithm so we don't need a date library for two timestamp formats
+fn utc_fields(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0) as i64;
+    let days = secs.div_euclid(86_400);
+    let seconds_of_day = secs.rem_euclid(86_400) as u32;
+
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60,
+    )
+}
@@ -0,0 +1,251 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use flate2::{write::GzEncoder, Compression};
+use super::{
+    request::Request,
+    response::Response,
+    router::{Middleware, Next},
+};
+
+// Bodies smaller than this aren't worth the CPU, the headers would eat the savings
+pub const MIN_COMPRESS_SIZE: u64 = 1024;
+
+// Bodies larger than this are streamed as-is rather than compressed in memory
+pub const MAX_COMPRESS_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    // Content-Encoding token for this encoding
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    // Extension of a precompressed sibling file, "styles.css" => "styles.css.br"
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gz",
+            Encoding::Brotli => "br",
+        }
+    }
+
+    // Every encoding, most preferred first. Precompressed files can be served
+    // in any of them since serving one doesn't require an encoder.
+    pub fn all() -> &'static [Encoding] {
+        &[Encoding::Brotli, Encoding::Gzip]
+    }
+
+    // Encodings this build can compress with on the fly, most preferred first
+    pub fn supported() -> &'static [Encoding] {
+        &[
+            #[cfg(feature = "brotli")]
+            Encoding::Brotli,
+            Encoding::Gzip,
+        ]
+    }
+}
+
+/// Pick the best encoding the client accepts from an `Accept-Encoding` header.
+///
+/// Our own preference order breaks ties between equal q-values, and an
+/// encoding listed with `q=0` is never chosen, even when `*` would allow it.
+pub fn negotiate(accept_encoding: Option<&str>) -> Option<Encoding> {
+    negotiate_among(accept_encoding, Encoding::supported())
+}
+
+/// Like `negotiate`, but only considering `candidates`, in their given order.
+pub fn negotiate_among(accept_encoding: Option<&str>, candidates: &[Encoding]) -> Option<Encoding> {
+    let accept_encoding = accept_encoding?;
+
+    // (coding, q-value) pairs as sent by the client
+    let accepted: Vec<(String, f32)> = accept_encoding
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let coding = params.next()?.trim().to_ascii_lowercase();
+            if coding.is_empty() {
+                return None;
+            }
+            let quality = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .find_map(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            Some((coding, quality))
+        })
+        .collect();
+
+    let quality_of = |name: &str| {
+        accepted
+            .iter()
+            .find(|(coding, _)| coding == name)
+            .or_else(|| accepted.iter().find(|(coding, _)| coding == "*"))
+            .map(|(_, quality)| *quality)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in candidates {
+        let quality = quality_of(encoding.name());
+        if quality > 0.0 && best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((*encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Whether responses of this content type benefit from compression.
+///
+/// Images, video, fonts and archives are already compressed.
+pub fn is_compressible(content_type: &str) -> bool {
+    let mime = content_type.split(';').next().unwrap_or("").trim();
+    mime.starts_with("text/")
+        || matches!(
+            mime,
+            "application/javascript"
+                | "application/json"
+                | "application/xml"
+                | "application/rss+xml"
+                | "application/atom+xml"
+                | "application/manifest+json"
+                | "image/svg+xml"
+        )
+}
+
+pub fn compress(data: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        #[cfg(feature = "brotli")]
+        Encoding::Brotli => {
+            let mut output = Vec::new();
+            {
+                // Quality 5 is a good trade-off for on-the-fly compression
+                let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+                encoder.write_all(data)?;
+            }
+            Ok(output)
+        }
+        #[cfg(not(feature = "brotli"))]
+        Encoding::Brotli => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "built without the brotli feature",
+        )),
+    }
+}
+
+// Each encoding of a resource is a distinct representation and needs its
+// own strong validator, so the coding is appended inside the quotes
+pub fn encoded_etag(etag: &str, encoding: Encoding) -> String {
+    format!("{}-{}\"", etag.trim_end_matches('"'), encoding.name())
+}
+
+/// Strip an encoding suffix added by `compress_response` from an entity tag,
+/// so conditional requests match the underlying file.
+pub fn strip_encoding_suffix(etag: &str) -> String {
+    for encoding in Encoding::all() {
+        let suffix = format!("-{}\"", encoding.name());
+        if let Some(stripped) = etag.strip_suffix(&suffix) {
+            return format!("{stripped}\"");
+        }
+    }
+    etag.to_string()
+}
+
+/// Find a precompressed sibling of `path` (written by `blog build --precompress`)
+/// in the best encoding the client accepts.
+///
+/// Siblings older than the file itself are stale and ignored.
+pub fn precompressed_sibling(request: &Request, path: &Path, modified: Option<SystemTime>) -> Option<(Encoding, PathBuf)> {
+    let accept_encoding = request.header("Accept-Encoding")?;
+
+    let fresh_siblings: Vec<(Encoding, PathBuf)> = Encoding::all()
+        .iter()
+        .filter_map(|encoding| {
+            let mut sibling = path.as_os_str().to_owned();
+            sibling.push(".");
+            sibling.push(encoding.extension());
+            let sibling = PathBuf::from(sibling);
+
+            let sibling_modified = fs::metadata(&sibling).and_then(|metadata| metadata.modified()).ok()?;
+            match modified {
+                Some(modified) if sibling_modified < modified => None,
+                _ => Some((*encoding, sibling)),
+            }
+        })
+        .collect();
+
+    let candidates: Vec<Encoding> = fresh_siblings.iter().map(|(encoding, _)| *encoding).collect();
+    let encoding = negotiate_among(Some(accept_encoding), &candidates)?;
+    fresh_siblings.into_iter().find(|(candidate, _)| *candidate == encoding)
+}
+
+/// Middleware compressing responses with `compress_response`.
+pub struct Compress;
+
+impl Middleware for Compress {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Response {
+        compress_response(request, next.run(request))
+    }
+}
+
+/// Compress a full `200 OK` response when the client accepts an encoding we
+/// support and the body is a compressible type within the size limits.
+pub fn compress_response(request: &Request, mut response: Response) -> Response {
+    if response.status != 200 || response.header("Content-Encoding").is_some() {
+        return response;
+    }
+
+    let compressible = response
+        .header("Content-Type")
+        .map(is_compressible)
+        .unwrap_or(false);
+    if !compressible {
+        return response;
+    }
+
+    // Caches must key compressible responses on Accept-Encoding, whether or
+    // not this particular one ends up compressed
+    response = response.with_header("Vary", "Accept-Encoding");
+
+    let size = response.body.len();
+    if !(MIN_COMPRESS_SIZE..=MAX_COMPRESS_SIZE).contains(&size) {
+        return response;
+    }
+
+    let Some(encoding) = negotiate(request.header("Accept-Encoding")) else {
+        return response;
+    };
+
+    let body = std::mem::replace(&mut response.body, super::response::Body::Empty);
+    let compressed = body
+        .into_bytes()
+        .and_then(|data| compress(&data, encoding));
+    let compressed = match compressed {
+        Ok(compressed) => compressed,
+        Err(err) => {
+            eprintln!("Failed to compress response: {}", err);
+            return super::error_response(500);
+        }
+    };
+
+    if let Some(etag) = response.header("ETag").map(|etag| encoded_etag(etag, encoding)) {
+        response.set_header("ETag", &etag);
+    }
+    response
+        .with_header("Content-Encoding", encoding.name())
+        .with_body(compressed)
+}
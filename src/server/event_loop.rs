@@ -0,0 +1,500 @@
+//! Event-driven connection handling, an alternative to a worker per
+//! connection that keeps slow or idle clients from tying up the pool.
+//!
+//! A single thread owns every socket and only reads and writes when the OS
+//! says it won't block. Once a full request has arrived it is answered on the
+//! pool with the same `respond` the blocking server uses, and the finished
+//! response is handed back to the loop to write out. File bodies are read a
+//! chunk at a time as the socket takes them, never into memory as a whole.
+
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, Read, Write},
+    net::SocketAddr,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant, SystemTime},
+};
+
+use mio::{
+    event::Event,
+    net::{TcpListener, TcpStream},
+    Events, Interest, Poll, Token, Waker,
+};
+
+use super::{
+    access_log::Entry,
+    closing_error_response, finish_response,
+    request::{parse_request, ParseError, Request, MAX_BODY_BYTES, MAX_HEADER_BYTES, MAX_REQUEST_LINE, REQUEST_TIMEOUT},
+    respond,
+    response::{BodyReader, Response},
+    threadpool::ThreadPool,
+    ServerState, Shutdown, KEEP_ALIVE_TIMEOUT, SHUTDOWN_TIMEOUT,
+};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_CONNECTION: usize = 2;
+
+// How often the loop checks for timed out connections and a shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Size of each read from a socket
+const READ_CHUNK: usize = 16 * 1024;
+
+// How much of a response body is read ahead of the socket
+const WRITE_CHUNK: usize = 64 * 1024;
+
+// A response prepared on the pool, on its way back to the loop
+struct Prepared {
+    token: Token,
+    head: Vec<u8>,
+    body: BodyReader,
+    keep_alive: bool,
+}
+
+struct Connection {
+    stream: TcpStream,
+    peer_addr: SocketAddr,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    written: usize,
+    // The rest of the response, read into `output` as it empties
+    body: Option<BodyReader>,
+    // A request is being answered on the pool
+    busy: bool,
+    // Close once `output` has been written
+    closing: bool,
+    // The client won't send anything more
+    read_closed: bool,
+    served: u32,
+    last_active: Instant,
+    // When the first byte of a request still being received arrived
+    request_started: Option<Instant>,
+}
+
+/// Accept and serve connections until `shutdown` is triggered, then wait up
+/// to `SHUTDOWN_TIMEOUT` for the responses in flight.
+pub(super) fn run(listener: std::net::TcpListener, pool: ThreadPool, state: Arc<ServerState>, shutdown: Shutdown) {
+    if let Err(err) = serve(listener, &pool, &state, &shutdown) {
+        eprintln!("Event loop failed: {}", err);
+    }
+
+    if let Err(err) = pool.shutdown(SHUTDOWN_TIMEOUT) {
+        eprintln!("Abandoning requests still running: {}", err);
+    }
+}
+
+fn serve(listener: std::net::TcpListener, pool: &ThreadPool, state: &Arc<ServerState>, shutdown: &Shutdown) -> io::Result<()> {
+    let mut poll = Poll::new()?;
+    listener.set_nonblocking(true)?;
+    let mut listener = Some(TcpListener::from_std(listener));
+    if let Some(listener) = &mut listener {
+        poll.registry().register(listener, LISTENER, Interest::READABLE)?;
+    }
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    let (prepared_sender, prepared) = mpsc::channel();
+
+    let mut connections: HashMap<Token, Connection> = HashMap::new();
+    let mut next_token = FIRST_CONNECTION;
+    let mut events = Events::with_capacity(1024);
+    let mut shutdown_deadline = None;
+
+    loop {
+        if let Err(err) = poll.poll(&mut events, Some(POLL_INTERVAL)) {
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+
+        let mut ready: Vec<Token> = Vec::new();
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => {
+                    let Some(listener) = &listener else { continue };
+                    accept(listener, &poll, &mut connections, &mut next_token);
+                }
+                // Drained below on every pass
+                WAKER => {}
+                token => {
+                    if let Some(connection) = connections.get_mut(&token) {
+                        on_event(connection, event);
+                        ready.push(token);
+                    }
+                }
+            }
+        }
+
+        for Prepared { token, head, body, keep_alive } in prepared.try_iter() {
+            let Some(connection) = connections.get_mut(&token) else { continue };
+            connection.busy = false;
+            queue_output(connection, head, body, !keep_alive);
+            ready.push(token);
+        }
+
+        ready.sort_unstable();
+        ready.dedup();
+
+        for token in ready {
+            let Some(connection) = connections.get_mut(&token) else { continue };
+            let open = advance(connection, token, pool, state, shutdown, &prepared_sender, &waker);
+            if !open {
+                close(&poll, &mut connections, token);
+            }
+        }
+
+        close_timed_out(&poll, &mut connections, state);
+
+        if shutdown.is_triggered() {
+            // Stop accepting, and drop connections with nothing in progress
+            if let Some(mut listener) = listener.take() {
+                let _ = poll.registry().deregister(&mut listener);
+                println!("Shutting down.");
+                shutdown_deadline = Some(Instant::now() + SHUTDOWN_TIMEOUT);
+            }
+            let idle: Vec<Token> = connections
+                .iter()
+                .filter(|(_, connection)| !connection.busy && !connection.writing())
+                .map(|(token, _)| *token)
+                .collect();
+            for token in idle {
+                close(&poll, &mut connections, token);
+            }
+
+            let out_of_time = shutdown_deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if connections.is_empty() || out_of_time {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn accept(listener: &TcpListener, poll: &Poll, connections: &mut HashMap<Token, Connection>, next_token: &mut usize) {
+    loop {
+        let (mut stream, peer_addr) = match listener.accept() {
+            Ok(accepted) => accepted,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                eprintln!("Failed to establish a connection: {}", err);
+                return;
+            }
+        };
+
+        let token = Token(*next_token);
+        *next_token += 1;
+        if let Err(err) = poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+            eprintln!("Failed to configure connection: {}", err);
+            continue;
+        }
+
+        connections.insert(
+            token,
+            Connection {
+                stream,
+                peer_addr,
+                input: Vec::new(),
+                output: Vec::new(),
+                written: 0,
+                body: None,
+                busy: false,
+                closing: false,
+                read_closed: false,
+                served: 0,
+                last_active: Instant::now(),
+                request_started: None,
+            },
+        );
+    }
+}
+
+// A hangup is noticed when reading returns 0, after any data still buffered
+fn on_event(connection: &mut Connection, event: &Event) {
+    if event.is_error() {
+        connection.closing = true;
+        connection.output.clear();
+        connection.body = None;
+    }
+}
+
+impl Connection {
+    // Part of a response is still waiting to be written
+    fn writing(&self) -> bool {
+        !self.output.is_empty() || self.body.is_some()
+    }
+}
+
+// Read what has arrived, write what is pending and start on the next request.
+// Returns false once the connection should be closed.
+fn advance(
+    connection: &mut Connection,
+    token: Token,
+    pool: &ThreadPool,
+    state: &Arc<ServerState>,
+    shutdown: &Shutdown,
+    prepared: &mpsc::Sender<Prepared>,
+    waker: &Arc<Waker>,
+) -> bool {
+    if read_available(connection).is_err() || flush(connection).is_err() {
+        return false;
+    }
+
+    loop {
+        if connection.busy || connection.writing() {
+            return true;
+        }
+        if connection.closing {
+            return false;
+        }
+
+        let mut reader = BufferReader::new(&connection.input);
+        let parsed = parse_request(&mut reader);
+        let consumed = reader.position;
+        let incomplete = reader.exhausted;
+
+        match parsed {
+            Ok(request) => {
+                connection.input.drain(..consumed);
+                connection.request_started = None;
+                connection.served += 1;
+                dispatch(connection, token, request, pool, state, shutdown, prepared, waker);
+            }
+            // Everything that arrived so far is a valid start of a request
+            Err(_) if incomplete => {
+                if connection.read_closed {
+                    return false;
+                }
+                if connection.request_started.is_none() && connection.input.iter().any(|byte| !byte.is_ascii_whitespace()) {
+                    connection.request_started = Some(Instant::now());
+                }
+                return true;
+            }
+            Err(err) => {
+                let Some(status) = err.status() else { return false };
+                eprintln!("{}: {}", connection.peer_addr, err);
+                reject(connection, state, closing_error_response(status), status);
+            }
+        }
+
+        if flush(connection).is_err() {
+            return false;
+        }
+    }
+}
+
+// Answer `request` on the pool, or turn the client away when it's full
+#[allow(clippy::too_many_arguments)]
+fn dispatch(
+    connection: &mut Connection,
+    token: Token,
+    request: Request,
+    pool: &ThreadPool,
+    state: &Arc<ServerState>,
+    shutdown: &Shutdown,
+    prepared: &mpsc::Sender<Prepared>,
+    waker: &Arc<Waker>,
+) {
+    let served = connection.served;
+    let peer_addr = connection.peer_addr;
+    let job_state = Arc::clone(state);
+    let job_shutdown = shutdown.clone();
+    let job_prepared = prepared.clone();
+    let job_waker = Arc::clone(waker);
+
+    let queued = pool.try_execute(move || {
+        let received = SystemTime::now();
+        let start = Instant::now();
+        let response = respond(&request, &job_state);
+        let (response, keep_alive) = finish_response(&request, response, served, &job_shutdown);
+
+        let status = response.status;
+        let (head, body) = response.into_parts(request.method != "HEAD");
+        // Logged when the response is ready, the loop writes it out afterwards
+        job_state.record(&Entry {
+            time: received,
+            peer: peer_addr,
+            request: Some(&request),
+            status,
+            bytes: body.len(),
+            latency: start.elapsed(),
+        });
+
+        let _ = job_prepared.send(Prepared {
+            token,
+            head,
+            body: body.into_reader(),
+            keep_alive,
+        });
+        let _ = job_waker.wake();
+    });
+
+    match queued {
+        Ok(()) => connection.busy = true,
+        Err(err) => {
+            eprintln!("Turning away a connection: {}", err);
+            reject(connection, state, closing_error_response(503).with_header("Retry-After", "1"), 503);
+        }
+    }
+}
+
+// Answer directly from the loop and close the connection afterwards
+fn reject(connection: &mut Connection, state: &ServerState, response: Response, status: u16) {
+    let received = SystemTime::now();
+    let start = Instant::now();
+    let (head, body) = response.into_parts(true);
+    state.record(&Entry {
+        time: received,
+        peer: connection.peer_addr,
+        request: None,
+        status,
+        bytes: body.len(),
+        latency: start.elapsed(),
+    });
+    queue_output(connection, head, body.into_reader(), true);
+}
+
+fn queue_output(connection: &mut Connection, head: Vec<u8>, body: BodyReader, close_after: bool) {
+    connection.output = head;
+    connection.written = 0;
+    connection.body = Some(body);
+    connection.closing |= close_after;
+}
+
+// Read until the socket would block, up to what one request may need
+fn read_available(connection: &mut Connection) -> io::Result<()> {
+    let limit = MAX_REQUEST_LINE + MAX_HEADER_BYTES + MAX_BODY_BYTES as usize;
+    let mut chunk = [0; READ_CHUNK];
+
+    while !connection.read_closed && connection.input.len() < limit {
+        match connection.stream.read(&mut chunk) {
+            Ok(0) => connection.read_closed = true,
+            Ok(read) => {
+                connection.input.extend_from_slice(&chunk[..read]);
+                connection.last_active = Instant::now();
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// Write as much pending output as the socket takes, reading ahead in the
+// body whenever less than a chunk of it is waiting
+fn flush(connection: &mut Connection) -> io::Result<()> {
+    loop {
+        if connection.output.len() - connection.written < WRITE_CHUNK {
+            if let Some(body) = &mut connection.body {
+                connection.output.drain(..connection.written);
+                connection.written = 0;
+                // Topping up before writing sends the head together with the
+                // start of the body, rather than as a small packet of its own
+                match body.take(WRITE_CHUNK as u64).read_to_end(&mut connection.output) {
+                    Ok(0) => connection.body = None,
+                    Ok(_) => {}
+                    // The head is out already, a failing body can only end the connection
+                    Err(err) => {
+                        eprintln!("{}: Error sending response: {}", connection.peer_addr, err);
+                        return Err(err);
+                    }
+                }
+            }
+        }
+
+        if connection.written == connection.output.len() {
+            connection.output.clear();
+            connection.written = 0;
+            return Ok(());
+        }
+
+        match connection.stream.write(&connection.output[connection.written..]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                connection.written += written;
+                connection.last_active = Instant::now();
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Idle keep-alive connections and requests that take too long to arrive
+fn close_timed_out(poll: &Poll, connections: &mut HashMap<Token, Connection>, state: &ServerState) {
+    let now = Instant::now();
+    let mut expired = Vec::new();
+
+    for (token, connection) in connections.iter_mut() {
+        if connection.busy || connection.writing() {
+            continue;
+        }
+        match connection.request_started {
+            Some(started) if now.duration_since(started) > REQUEST_TIMEOUT => {
+                eprintln!("{}: {}", connection.peer_addr, ParseError::Timeout);
+                connection.request_started = None;
+                reject(connection, state, closing_error_response(408), 408);
+                // Whatever doesn't fit in the socket now is written on the
+                // next writable event, and the connection closed after it
+                if flush(connection).is_err() || !connection.writing() {
+                    expired.push(*token);
+                }
+            }
+            None if now.duration_since(connection.last_active) > KEEP_ALIVE_TIMEOUT => expired.push(*token),
+            _ => {}
+        }
+    }
+
+    for token in expired {
+        close(poll, connections, token);
+    }
+}
+
+fn close(poll: &Poll, connections: &mut HashMap<Token, Connection>, token: Token) {
+    if let Some(mut connection) = connections.remove(&token) {
+        let _ = poll.registry().deregister(&mut connection.stream);
+    }
+}
+
+// Lets `parse_request` work on the bytes received so far. Running out of
+// them reads as WouldBlock, so a partial request is told apart from a bad one.
+struct BufferReader<'a> {
+    buffer: &'a [u8],
+    position: usize,
+    exhausted: bool,
+}
+
+impl<'a> BufferReader<'a> {
+    fn new(buffer: &'a [u8]) -> BufferReader<'a> {
+        BufferReader {
+            buffer,
+            position: 0,
+            exhausted: false,
+        }
+    }
+}
+
+impl Read for BufferReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let read = available.len().min(buf.len());
+        buf[..read].copy_from_slice(&available[..read]);
+        self.consume(read);
+        Ok(read)
+    }
+}
+
+impl BufRead for BufferReader<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.buffer.len() {
+            self.exhausted = true;
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(&self.buffer[self.position..])
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.position += amount;
+    }
+}
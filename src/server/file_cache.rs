@@ -0,0 +1,154 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::SystemTime,
+};
+
+// A single file may take up at most this fraction of the cache, so one large
+// download doesn't push out every page
+const MAX_FILE_SHARE: u64 = 8;
+
+/// Contents of recently served files, keyed by path, evicting the least
+/// recently used ones once `max_bytes` is exceeded.
+///
+/// Entries are checked against the file's size and modification time on
+/// every lookup, `clear` drops everything at once after a rebuild.
+pub struct FileCache {
+    max_bytes: u64,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Inner {
+    entries: HashMap<PathBuf, Entry>,
+    // Last use => path, oldest first
+    recency: BTreeMap<u64, PathBuf>,
+    clock: u64,
+    bytes: u64,
+}
+
+struct Entry {
+    contents: Arc<[u8]>,
+    modified: Option<SystemTime>,
+    last_used: u64,
+}
+
+/// Numbers for the status endpoint.
+#[derive(Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub max_bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl FileCache {
+    pub fn new(max_bytes: u64) -> FileCache {
+        FileCache {
+            max_bytes,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                bytes: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The contents of the file at `path`, which `metadata` describes, read
+    /// from disk unless a current copy is cached. `None` for files too large
+    /// to cache or that can't be read, the caller streams those itself.
+    pub fn get(&self, path: &Path, metadata: &fs::Metadata) -> Option<Arc<[u8]>> {
+        let len = metadata.len();
+        if len > self.max_bytes / MAX_FILE_SHARE {
+            return None;
+        }
+        let modified = metadata.modified().ok();
+
+        {
+            let mut inner = self.lock();
+            let inner = &mut *inner;
+            inner.clock += 1;
+            if let Some(entry) = inner.entries.get_mut(path) {
+                if entry.contents.len() as u64 == len && entry.modified == modified {
+                    inner.recency.remove(&entry.last_used);
+                    inner.recency.insert(inner.clock, path.to_path_buf());
+                    entry.last_used = inner.clock;
+                    self.hits.fetch_add(1, Ordering::Relaxed);
+                    return Some(Arc::clone(&entry.contents));
+                }
+            }
+        }
+
+        // Read without holding the lock, other requests keep being served
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let contents: Arc<[u8]> = fs::read(path).ok()?.into();
+        // Changed while we were reading, don't remember a mix of both versions
+        if contents.len() as u64 != len {
+            return None;
+        }
+
+        let mut inner = self.lock();
+        inner.remove(path);
+        inner.clock += 1;
+        let last_used = inner.clock;
+        inner.recency.insert(last_used, path.to_path_buf());
+        inner.bytes += len;
+        inner.entries.insert(
+            path.to_path_buf(),
+            Entry {
+                contents: Arc::clone(&contents),
+                modified,
+                last_used,
+            },
+        );
+        while inner.bytes > self.max_bytes {
+            let Some((_, oldest)) = inner.recency.pop_first() else { break };
+            inner.remove(&oldest);
+        }
+
+        Some(contents)
+    }
+
+    /// Forget every file, e.g. because the site was rebuilt.
+    pub fn clear(&self) {
+        let mut inner = self.lock();
+        inner.entries.clear();
+        inner.recency.clear();
+        inner.bytes = 0;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.lock();
+        CacheStats {
+            entries: inner.entries.len(),
+            bytes: inner.bytes,
+            max_bytes: self.max_bytes,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    // Nothing can panic while the lock is held, the cache is never left half updated
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.entries.remove(path) {
+            self.recency.remove(&entry.last_used);
+            self.bytes -= entry.contents.len() as u64;
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use std::{fs, io, path::Path, time::SystemTime};
+
+use maud::{html, DOCTYPE};
+
+use super::{resolve::percent_decode, response::Response};
+
+// One row of the listing
+struct Item {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// An HTML page listing the contents of `dir`, which is served at
+/// `url_path`. Directories come first, then files, each sorted by name.
+pub fn directory_listing(dir: &Path, url_path: &str) -> io::Result<Response> {
+    let mut items = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        // Follows symlinks, broken ones are left out
+        let Ok(metadata) = fs::metadata(entry.path()) else { continue };
+        items.push(Item {
+            name: entry.file_name().to_string_lossy().into_owned(),
+            is_dir: metadata.is_dir(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        });
+    }
+    items.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    let title = format!("Index of {}", percent_decode(url_path).unwrap_or_else(|| url_path.to_string()));
+    let page = html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                title { (title) }
+                style { "body{font-family:monospace;margin:2em}td{padding:0 1.5em 0 0}.size{text-align:right}" }
+            }
+            body {
+                h1 { (title) }
+                table {
+                    tr { th { "Name" } th class="size" { "Size" } th { "Modified" } }
+                    @if url_path != "/" {
+                        tr { td { a href="../" { "../" } } td {} td {} }
+                    }
+                    @for item in &items {
+                        @let suffix = if item.is_dir { "/" } else { "" };
+                        tr {
+                            td { a href={ (percent_encode(&item.name)) (suffix) } { (item.name) (suffix) } }
+                            td class="size" { @if !item.is_dir { (human_size(item.size)) } }
+                            td { @if let Some(modified) = item.modified { (httpdate::fmt_http_date(modified)) } }
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    Ok(Response::new(200)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        // The listing changes with every build, never reuse an old copy
+        .with_header("Cache-Control", "no-cache")
+        .with_body(page.into_string().into_bytes()))
+}
+
+// "a b#1.html" => "a%20b%231.html", so any file name works as a relative link
+fn percent_encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+// 1536 => "1.5 KiB"
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
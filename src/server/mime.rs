@@ -0,0 +1,95 @@
+use std::collections::HashMap;
+
+// Served when neither the overrides nor the built-in table know an extension
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+// Built-in extension => MIME type table, extensions are lowercase
+fn builtin_type(ext: &str) -> Option<&'static str> {
+    let mime = match ext {
+        // Documents
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" | "map" => "application/json",
+        "webmanifest" => "application/manifest+json",
+        "xml" | "xsl" => "application/xml",
+        "rss" => "application/rss+xml",
+        "atom" => "application/atom+xml",
+        "txt" => "text/plain",
+        "md" | "markdown" => "text/markdown",
+        "csv" => "text/csv",
+        "vtt" => "text/vtt",
+        "yaml" | "yml" => "application/yaml",
+        "toml" => "application/toml",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+
+        // Images
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "bmp" => "image/bmp",
+        "tif" | "tiff" => "image/tiff",
+
+        // Fonts
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "eot" => "application/vnd.ms-fontobject",
+
+        // Audio and video
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "ogv" => "video/ogg",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "aac" => "audio/aac",
+        "oga" | "ogg" => "audio/ogg",
+        "opus" => "audio/opus",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+
+        // Archives, including precompressed siblings requested directly
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "br" => "application/x-brotli",
+        "tar" => "application/x-tar",
+
+        _ => return None,
+    };
+    Some(mime)
+}
+
+// Text based types are served as UTF-8, which is what the generator writes
+fn is_text(mime: &str) -> bool {
+    mime.starts_with("text/")
+        || mime.ends_with("+xml")
+        || mime.ends_with("+json")
+        || matches!(mime, "application/json" | "application/xml" | "application/yaml" | "application/toml")
+}
+
+/// Content-Type for a file extension, consulting `overrides` first.
+///
+/// Text types get a `; charset=utf-8` parameter unless the type already
+/// carries parameters of its own.
+pub fn content_type(ext: &str, overrides: &HashMap<String, String>) -> String {
+    let ext = ext.to_ascii_lowercase();
+
+    let mime = overrides
+        .get(&ext)
+        .map(String::as_str)
+        .or_else(|| builtin_type(&ext))
+        .unwrap_or(DEFAULT_CONTENT_TYPE);
+
+    if !mime.contains(';') && is_text(mime) {
+        format!("{mime}; charset=utf-8")
+    } else {
+        mime.to_string()
+    }
+}
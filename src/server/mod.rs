@@ -1,168 +1,1073 @@
+pub mod access_log;
+mod compression;
+#[cfg(feature = "event-loop")]
+mod event_loop;
+pub mod file_cache;
+mod listing;
+mod mime;
+mod network;
+mod range;
+pub mod request;
+mod resolve;
+pub mod response;
+pub mod router;
+mod rules;
+pub mod status;
 pub mod threadpool;
+pub mod tls;
 
 use std::{
-    fs, io::{prelude::*, BufReader}, net::{TcpListener, TcpStream}, path::{Path, PathBuf},
+    fs, io::{self, BufReader, Read, Write}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicBool, Ordering}, Arc, OnceLock}, thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use threadpool::ThreadPool;
+use crate::config::Config;
+use access_log::{AccessLog, Entry, LogFormat};
+use file_cache::FileCache;
+use range::{parse_range, ByteRange, RangeRequest};
+use request::{parse_request, Request, REQUEST_TIMEOUT};
+use resolve::{resolve, Resolved};
+use response::Response;
+use router::{Handler, Middleware, Router};
+use status::{BuildReport, Status, StatusHandler, STATUS_PATH};
+use threadpool::{Metrics, Monitor, ThreadPool, DEFAULT_QUEUE_CAPACITY};
+use tls::TlsConfig;
 
-pub fn server_create(port: u16) {
-    // Bind to the specified port
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).unwrap_or_else(|err| {
-        eprintln!("Failed to bind to address: {}", err);
-        std::process::exit(1);
-    });
+// How long triggering a shutdown may take to reach a blocked accept loop
+const WAKE_TIMEOUT: Duration = Duration::from_secs(1);
 
-    // Create a thread pool with 4 threads
-    let pool = ThreadPool::new(4);
+// How long a new connection may wait for room in a full queue before it is
+// turned away with a 503
+const QUEUE_TIMEOUT: Duration = Duration::from_millis(100);
 
-    // Handle incoming connections
-    loop {
-        for stream in listener.incoming() {
-            // Check stream validity
-            let stream = match stream {
-                Ok(stream) => {
-                    let _peer_addr = match stream.peer_addr() {
-                        Ok(addr) => addr,
-                        Err(err) => {
-                            println!("unknown address: {}", err);
-                            continue;
-                        }
-                    };
-                    // println!("New connection from {peer_addr}");
-                    stream
-                }
-                Err(err) => {
-                    eprintln!("Failed to establish a connection: {}", err);
-                    continue;
-                }
+// How long in-flight requests get to finish once shutdown starts
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Cloneable flag telling a running server to stop.
+///
+/// Triggering it makes the server stop accepting connections and finish the
+/// requests it is already handling, after which `ServerHandle::wait` returns.
+#[derive(Clone, Default, Debug)]
+pub struct Shutdown(Arc<ShutdownState>);
+
+#[derive(Default, Debug)]
+struct ShutdownState {
+    triggered: AtomicBool,
+    // The listener to connect to once triggered, waking a blocking accept
+    wake_addr: OnceLock<SocketAddr>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Shutdown::default()
+    }
+
+    pub fn trigger(&self) {
+        // Only the first trigger needs to wake the server
+        if self.0.triggered.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if let Some(addr) = self.0.wake_addr.get() {
+            // The connection is dropped right away, accept returning is enough
+            if let Err(err) = TcpStream::connect_timeout(addr, WAKE_TIMEOUT) {
+                eprintln!("Failed to wake the server for shutdown: {}", err);
+            }
+        }
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.0.triggered.load(Ordering::SeqCst)
+    }
+
+    // Have `trigger` wake whoever is blocked accepting on `local_addr`
+    fn wake_on(&self, local_addr: SocketAddr) {
+        let mut addr = local_addr;
+        if addr.ip().is_unspecified() {
+            let loopback = match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
             };
+            addr.set_ip(loopback);
+        }
+        let _ = self.0.wake_addr.set(addr);
+    }
+}
+
+// State shared by every connection of a running server
+struct ServerState {
+    access_log: AccessLog,
+    tls: Option<TlsConfig>,
+    https_redirect: bool,
+    status: Arc<Status>,
+    router: Router,
+}
+
+// The generated site, answering every request no other route takes
+struct Site {
+    config: Config,
+    root: PathBuf,
+    file_cache: Option<Arc<FileCache>>,
+    directory_listing: bool,
+}
+
+impl ServerState {
+    // Every response goes through here, for the access log and the status counters
+    fn record(&self, entry: &Entry) {
+        self.access_log.log(entry);
+        self.status.record_response(entry.status, entry.bytes);
+    }
+}
+
+/// Builder for the dev server.
+///
+/// # Examples
+/// ```no_run
+/// use blog::{config::Config, server::Server};
+///
+/// // Port 0 picks a free ephemeral port
+/// let server = Server::new(Config::default())
+///     .with_port(0)
+///     .with_root("output")
+///     .start()
+///     .expect("server failed to start");
+///
+/// println!("Listening on http://{}", server.local_addr());
+/// server.shutdown();
+/// ```
+pub struct Server {
+    address: IpAddr,
+    port: u16,
+    root: PathBuf,
+    min_workers: u32,
+    max_workers: u32,
+    queue_capacity: usize,
+    config: Config,
+    access_log: AccessLog,
+    tls: Option<TlsConfig>,
+    https_redirect: bool,
+    status_endpoint: bool,
+    file_cache_bytes: Option<u64>,
+    directory_listing: bool,
+    router: Router,
+    #[cfg(feature = "event-loop")]
+    event_loop: bool,
+}
+
+impl Server {
+    /// A server for `config` on 127.0.0.1:8080, serving `output` with one
+    /// worker per CPU and logging requests to stdout in Combined Log Format.
+    pub fn new(config: Config) -> Server {
+        let workers = threadpool::default_size();
+        Server {
+            address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8080,
+            root: PathBuf::from("output"),
+            min_workers: workers,
+            max_workers: workers,
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            config,
+            access_log: AccessLog::stdout(LogFormat::Combined),
+            tls: None,
+            https_redirect: false,
+            status_endpoint: false,
+            file_cache_bytes: None,
+            directory_listing: false,
+            router: Router::default(),
+            #[cfg(feature = "event-loop")]
+            event_loop: false,
+        }
+    }
+
+    pub fn with_address(mut self, address: IpAddr) -> Server {
+        self.address = address;
+        self
+    }
+
+    // Port 0 lets the OS pick a free port, see `ServerHandle::local_addr`
+    pub fn with_port(mut self, port: u16) -> Server {
+        self.port = port;
+        self
+    }
+
+    // Directory the site is served from
+    pub fn with_root(mut self, root: impl Into<PathBuf>) -> Server {
+        self.root = root.into();
+        self
+    }
+
+    // Number of threads handling connections
+    pub fn with_workers(mut self, workers: u32) -> Server {
+        self.min_workers = workers;
+        self.max_workers = workers;
+        self
+    }
+
+    // Start with `min` threads, adding more up to `max` while connections
+    // wait for one and letting the extra ones go once they sit idle
+    pub fn with_worker_limits(mut self, min: u32, max: u32) -> Server {
+        self.min_workers = min;
+        self.max_workers = max;
+        self
+    }
+
+    // Connections that may wait for a free worker, more get a 503
+    pub fn with_queue_capacity(mut self, queue_capacity: usize) -> Server {
+        self.queue_capacity = queue_capacity;
+        self
+    }
+
+    // Where and how every request is logged
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Server {
+        self.access_log = access_log;
+        self
+    }
+
+    // Serve HTTPS instead of plain HTTP
+    pub fn with_tls(mut self, tls: TlsConfig) -> Server {
+        self.tls = Some(tls);
+        self
+    }
+
+    // With TLS on, redirect plain HTTP requests made to the same port to
+    // https instead of rejecting them
+    pub fn with_https_redirect(mut self, https_redirect: bool) -> Server {
+        self.https_redirect = https_redirect;
+        self
+    }
+
+    // Answer `STATUS_PATH` with request counts, pool metrics and the last
+    // build as JSON
+    pub fn with_status_endpoint(mut self, status_endpoint: bool) -> Server {
+        self.status_endpoint = status_endpoint;
+        self
+    }
+
+    // Keep up to `max_bytes` of recently served files in memory
+    pub fn with_file_cache(mut self, max_bytes: u64) -> Server {
+        self.file_cache_bytes = Some(max_bytes);
+        self
+    }
+
+    // List the contents of directories that have no index.html instead of
+    // answering 404
+    pub fn with_directory_listing(mut self, directory_listing: bool) -> Server {
+        self.directory_listing = directory_listing;
+        self
+    }
 
-            // Add job to pool
-            pool.execute(move || {
-                if let Err(err) = handle_connection(stream) {
+    /// Answer `methods` requests for `prefix` and the paths below it with
+    /// `handler` instead of the site. The longest matching prefix wins, and
+    /// a route taking GET takes HEAD as well.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use blog::{config::Config, server::{response::Response, Server}};
+    ///
+    /// let server = Server::new(Config::default())
+    ///     .with_route(&["GET"], "/api/ping", |_: &_| Response::new(200).with_body(b"pong".to_vec()))
+    ///     .start();
+    /// ```
+    pub fn with_route(mut self, methods: &[&str], prefix: &str, handler: impl Handler + 'static) -> Server {
+        self.router.add_route(methods, prefix, Box::new(handler));
+        self
+    }
+
+    /// Run `middleware` around every routed request, inside the built-in
+    /// compression and custom headers and in the order it was added.
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Server {
+        self.router.add_middleware(Box::new(middleware));
+        self
+    }
+
+    // Serve every connection from one non-blocking event loop, answering
+    // requests on the workers, instead of giving each connection a worker
+    #[cfg(feature = "event-loop")]
+    pub fn with_event_loop(mut self, event_loop: bool) -> Server {
+        self.event_loop = event_loop;
+        self
+    }
+
+    /// Bind the listener and start accepting connections on a background thread.
+    pub fn start(mut self) -> Result<ServerHandle, Box<dyn std::error::Error>> {
+        // Extensions are matched case-insensitively and without a leading dot
+        self.config.server.mime_types = self
+            .config
+            .server
+            .mime_types
+            .into_iter()
+            .map(|(ext, mime)| (ext.trim_start_matches('.').to_ascii_lowercase(), mime))
+            .collect();
+
+        #[cfg(feature = "event-loop")]
+        if self.event_loop && self.tls.is_some() {
+            return Err("TLS is not supported with the event loop".into());
+        }
+
+        // Bind to the specified address and port
+        let listener = TcpListener::bind((self.address, self.port))
+            .map_err(|err| format!("Failed to bind to {}:{}: {}", self.address, self.port, err))?;
+
+        let local_addr = listener.local_addr()?;
+
+        let pool = ThreadPool::build_elastic(self.min_workers, self.max_workers, self.queue_capacity)?;
+
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        let status = Arc::new(Status::new());
+        let file_cache = self.file_cache_bytes.map(|max_bytes| Arc::new(FileCache::new(max_bytes)));
+        let pool_monitor = pool.monitor();
+
+        let mut router = self.router;
+        if self.status_endpoint {
+            let handler = StatusHandler::new(Arc::clone(&status), pool_monitor.clone(), file_cache.clone());
+            router.add_route(&["GET"], STATUS_PATH, Box::new(handler));
+        }
+        // Custom headers apply to compressed responses too, so they go first
+        router.wrap(Box::new(compression::Compress));
+        router.wrap(Box::new(rules::CustomHeaders::new(self.config.headers.clone())));
+        router.set_fallback(Box::new(Site {
+            config: self.config,
+            root: self.root,
+            file_cache: file_cache.clone(),
+            directory_listing: self.directory_listing,
+        }));
+
+        let state = Arc::new(ServerState {
+            access_log: self.access_log,
+            tls: self.tls,
+            https_redirect: self.https_redirect,
+            status: Arc::clone(&status),
+            router,
+        });
+        let shutdown = Shutdown::new();
+        shutdown.wake_on(local_addr);
+
+        #[cfg(feature = "event-loop")]
+        let serve = if self.event_loop { event_loop::run } else { accept_connections };
+        #[cfg(not(feature = "event-loop"))]
+        let serve = accept_connections;
+
+        let thread = {
+            let shutdown = shutdown.clone();
+            thread::Builder::new()
+                .name("blog-server".to_string())
+                .spawn(move || serve(listener, pool, state, shutdown))?
+        };
+
+        Ok(ServerHandle {
+            local_addr,
+            scheme,
+            shutdown,
+            status,
+            pool_monitor,
+            file_cache,
+            thread: Some(thread),
+        })
+    }
+}
+
+/// A running server. Dropping the handle shuts the server down.
+pub struct ServerHandle {
+    local_addr: SocketAddr,
+    scheme: &'static str,
+    shutdown: Shutdown,
+    status: Arc<Status>,
+    pool_monitor: Monitor,
+    file_cache: Option<Arc<FileCache>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on, with the actual port when 0 was requested.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// URLs the server can be reached at, including LAN addresses when
+    /// listening on a wildcard address.
+    pub fn urls(&self) -> Vec<String> {
+        network::reachable_urls(self.scheme, self.local_addr)
+    }
+
+    /// Whether the server accepts connections from other machines.
+    pub fn is_exposed(&self) -> bool {
+        network::is_exposed(self.local_addr)
+    }
+
+    /// Queue length, busy workers and job timings of the worker pool.
+    pub fn pool_metrics(&self) -> Metrics {
+        self.pool_monitor.metrics()
+    }
+
+    /// Drop every cached file, call after the output directory was rebuilt.
+    pub fn clear_file_cache(&self) {
+        if let Some(file_cache) = &self.file_cache {
+            file_cache.clear();
+        }
+    }
+
+    /// Tell the status endpoint how the latest site build went.
+    pub fn report_build(&self, report: BuildReport) {
+        self.status.record_build(report);
+    }
+
+    /// The flag that stops this server, for signal handlers and other threads.
+    pub fn shutdown_signal(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Block until the server stops after its shutdown signal is triggered.
+    pub fn wait(mut self) {
+        self.join();
+    }
+
+    /// Stop the server, waiting up to `SHUTDOWN_TIMEOUT` for in-flight requests.
+    pub fn shutdown(mut self) {
+        self.shutdown.trigger();
+        self.join();
+    }
+
+    fn join(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                eprintln!("Server thread panicked");
+            }
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.shutdown.trigger();
+        self.join();
+    }
+}
+
+fn accept_connections(listener: TcpListener, pool: ThreadPool, state: Arc<ServerState>, shutdown: Shutdown) {
+    // Handle incoming connections
+    while !shutdown.is_triggered() {
+        // Check stream validity
+        let stream = match listener.accept() {
+            Ok((stream, _peer_addr)) => {
+                // println!("New connection from {peer_addr}");
+                stream
+            }
+            Err(err) => {
+                eprintln!("Failed to establish a connection: {}", err);
+                continue;
+            }
+        };
+
+        // Most likely the connection `Shutdown::trigger` makes to wake us up
+        if shutdown.is_triggered() {
+            break;
+        }
+
+        // Keep a handle to answer the client ourselves if the pool won't take it
+        let overflow = stream.try_clone();
+
+        // Add job to pool
+        let job_state = Arc::clone(&state);
+        let job_shutdown = shutdown.clone();
+        let queued = pool.execute_timeout(
+            move || {
+                if let Err(err) = handle_connection(stream, &job_state, &job_shutdown) {
                     eprintln!("Error handling connection: {}", err);
                 }
-            });
+            },
+            QUEUE_TIMEOUT,
+        );
+
+        if let Err(err) = queued {
+            eprintln!("Turning away a connection: {}", err);
+            if let Ok(stream) = overflow {
+                reject_overloaded(stream, &state);
+            }
         }
     }
 
-    // println!("Shutting down.");
+    println!("Shutting down.");
+    drop(listener);
+
+    // Give the workers a bounded amount of time to finish their current
+    // connection before giving up on them
+    if let Err(err) = pool.shutdown(SHUTDOWN_TIMEOUT) {
+        eprintln!("Abandoning requests still running: {}", err);
+    }
+}
+
+// Tells a client that couldn't be queued to come back later. The request is
+// never read, so the response goes out without waiting for it.
+fn reject_overloaded(stream: TcpStream, state: &ServerState) {
+    let Ok(peer_addr) = stream.peer_addr() else {
+        return;
+    };
+    // Never let a slow client hold up the accept loop
+    let _ = stream.set_write_timeout(Some(Duration::from_millis(100)));
+
+    let received = SystemTime::now();
+    let start = Instant::now();
+    let sent = closing_error_response(503)
+        .with_header("Retry-After", "1")
+        .write_to(&mut &stream, true);
+    state.record(&Entry {
+        time: received,
+        peer: peer_addr,
+        request: None,
+        status: 503,
+        bytes: sent.unwrap_or(0),
+        latency: start.elapsed(),
+    });
 }
 
-fn get_content_type(ext: &str) -> &str {
-    match ext {
-        "" => "text/html",
-        "html" => "text/html",
-        "css" => "text/css",
-        "png" => "image/png",
-        "jpg" | "jpeg" => "image/jpeg",
-        "gif" => "image/gif",
-        "js" => "application/javascript",
-        _ => "application/octet-stream", // Default content type for files with no extension or unknown extension
+// Methods the dev server knows how to answer, advertised in 405 responses
+const ALLOWED_METHODS: &str = "GET, HEAD";
+
+// How long a persistent connection may sit idle before it is closed
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Connections are closed after this many requests so one client can't hold
+// on to a worker forever
+const MAX_REQUESTS_PER_CONNECTION: u32 = 100;
+
+// Strong validator built from the file size and modification time
+fn entity_tag(metadata: &fs::Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", metadata.len(), modified)
+}
+
+// Checks the conditional request headers, If-None-Match takes precedence
+// over If-Modified-Since as required by RFC 9110
+fn is_not_modified(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = request.header("If-None-Match") {
+        // If-None-Match uses weak comparison, so W/ prefixes are ignored, and
+        // any compressed variant of the file matches the file itself
+        return if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || compression::strip_encoding_suffix(tag) == etag);
+    }
+
+    let (Some(if_modified_since), Some(modified)) = (request.header("If-Modified-Since"), modified) else {
+        return false;
+    };
+    match httpdate::parse_http_date(if_modified_since) {
+        // HTTP dates only have second precision
+        Ok(since) => httpdate::HttpDate::from(modified) <= httpdate::HttpDate::from(since),
+        Err(_) => false,
     }
 }
 
-// Encodes a response in response to a get request
-// Path => "/" "/assets/styles.css" "/blog_posts/sample"
-fn handle_get(mut stream: TcpStream, path: PathBuf) {
+// The client may hold a compressed variant of the file, validated with an
+// encoded entity tag, so the 304 echoes that tag and varies like the 200 did
+fn not_modified(request: &Request, etag: &str, content_type: &str) -> Response {
+    let validated = request.header("If-None-Match").and_then(|if_none_match| {
+        if_none_match
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .find(|tag| compression::strip_encoding_suffix(tag) == etag)
+    });
 
-    // Point / => home blog post
-    let path = if path.to_str() == Some("/") {
-        PathBuf::from("/index.html")
+    let response = Response::new(304).with_header("ETag", validated.unwrap_or(etag));
+    if compression::is_compressible(content_type) {
+        response.with_header("Vary", "Accept-Encoding")
     } else {
-        path
-    };
+        response
+    }
+}
 
+// If-Range holds either an entity tag or a date, ranges are only honored
+// when it still describes the current file
+fn if_range_matches(request: &Request, etag: &str, last_modified: Option<&str>) -> bool {
+    match request.header("If-Range") {
+        None => true,
+        // Weak tags never match for If-Range
+        Some(tag) if tag.starts_with('"') => tag == etag,
+        Some(date) => Some(date) == last_modified,
+    }
+}
 
-    // TODO: change hardcoded output to env
-    let output_dir = "output";
-    let path = Path::new("output").join(path);
+// A small html page describing an error status
+fn error_response(status: u16) -> Response {
+    let body = format!("<h1>{} {}</h1>", status, response::reason_phrase(status));
+    Response::new(status)
+        .with_header("Content-Type", "text/html; charset=utf-8")
+        .with_body(body.into_bytes())
+}
 
-    let ext = match path.extension() {
-        Some(ext) => ext.to_str().unwrap(),
-        None => "html"
-    };
+// Redirect to `location`, 301 for the canonical URL of a page
+fn redirect(status: u16, location: &str) -> Response {
+    error_response(status).with_header("Location", location)
+}
 
-    // Determine the type of content to send back
-    let content_type = get_content_type(ext);
+fn not_found() -> Response {
+    error_response(404)
+}
 
+// The response for a precompressed sibling file, it is a separate
+// representation of the resource so it gets its own entity tag
+fn precompressed_response(
+    encoding: compression::Encoding,
+    content_type: &str,
+    etag: &str,
+    last_modified: Option<&str>,
+) -> Response {
+    let response = Response::new(200)
+        .with_header("Content-Type", content_type)
+        .with_header("Content-Encoding", encoding.name())
+        .with_header("Vary", "Accept-Encoding")
+        .with_header("ETag", &compression::encoded_etag(etag, encoding));
 
-    // Construct the file path based on the extension
-    let full_path = {
-        format!("{}{}", output_dir, path.to_string_lossy())
+    match last_modified {
+        Some(last_modified) => response.with_header("Last-Modified", last_modified),
+        None => response,
+    }
+}
+
+// The whole file at `path` as the body of `response`
+fn with_file_body(response: Response, path: &Path, metadata: &fs::Metadata, site: &Site) -> io::Result<Response> {
+    // Ranges are inclusive, so an empty file has no range to send
+    match metadata.len() {
+        0 => Ok(response),
+        len => with_file_range(response, path, metadata, ByteRange { start: 0, end: len - 1 }, site),
+    }
+}
+
+// Sends `range` of the file from the file cache when it's on, and streams it
+// from disk otherwise
+fn with_file_range(
+    response: Response,
+    path: &Path,
+    metadata: &fs::Metadata,
+    range: ByteRange,
+    site: &Site,
+) -> io::Result<Response> {
+    if let Some(contents) = site.file_cache.as_ref().and_then(|cache| cache.get(path, metadata)) {
+        return Ok(response.with_cached(contents, range));
+    }
+    Ok(response.with_file(fs::File::open(path)?, range))
+}
+
+// Builds the response to a GET request, HEAD requests share it and
+// simply skip the body when it is written out
+fn handle_get(request: &Request, site: &Site) -> Response {
+    let config = &site.config;
+
+    // Configured redirects apply to paths without a file of their own, or to
+    // every path when forced, the same as on Netlify
+    let configured_redirect = match rules::find_redirect(&config.redirects, &request.path) {
+        Some((rule, location)) if rule.force => return redirect(rule.status, &location),
+        Some((rule, location)) => Some(redirect(rule.status, &location)),
+        None => None,
+    };
+
+    let path = match resolve(&request.path, &site.root, config.pretty_urls) {
+        Resolved::File(path) => path,
+        Resolved::Redirect(location) => return redirect(301, &location),
+        Resolved::Directory(dir) if site.directory_listing => return list_directory(request, &dir),
+        Resolved::Directory(_) | Resolved::NotFound => return configured_redirect.unwrap_or_else(not_found),
     };
-    
-    // Debug statement for full path
-    println!("Reading {full_path}");
 
-    let contents = match fs::read_to_string(&full_path) {
-        Ok(contents) => contents,
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("");
+
+    // Determine the type of content to send back
+    let content_type = mime::content_type(ext, &config.server.mime_types);
+    let content_type = content_type.as_str();
+
+    let metadata = match fs::metadata(&path) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        Ok(_) => return not_found(),
         Err(err) => {
-            eprintln!("Error reading contents of {}: {}", &full_path, err);
-            "<h1>404 Not Found</h1>".to_string()
+            eprintln!("Error reading contents of {}: {}", path.display(), err);
+            return not_found();
         }
     };
 
-    let status_line = if contents == "<h1>404 Not Found</h1>" {
-        "HTTP/1.1 404 NOT FOUND"
+    let etag = entity_tag(&metadata);
+    let modified = metadata.modified().ok();
+    let last_modified = modified.map(httpdate::fmt_http_date);
+
+    let response = if is_not_modified(request, &etag, modified) {
+        not_modified(request, &etag, content_type)
     } else {
-        "HTTP/1.1 200 OK"
+        let file_len = metadata.len();
+
+        // A Range only applies while the client's copy is still current
+        let range_request = match request.header("Range") {
+            Some(range) if if_range_matches(request, &etag, last_modified.as_deref()) => {
+                parse_range(range, file_len)
+            }
+            _ => RangeRequest::Full,
+        };
+
+        // Serve a precompressed sibling written at build time when there is one
+        let sibling = match range_request {
+            RangeRequest::Full if compression::is_compressible(content_type) => {
+                compression::precompressed_sibling(request, &path, modified)
+            }
+            _ => None,
+        };
+        if let Some((encoding, sibling)) = sibling {
+            if let Ok(sibling_metadata) = fs::metadata(&sibling) {
+                let response = precompressed_response(encoding, content_type, &etag, last_modified.as_deref());
+                if let Ok(response) = with_file_body(response, &sibling, &sibling_metadata, site) {
+                    return response;
+                }
+            }
+        }
+
+        let response = match range_request {
+            RangeRequest::Full => with_file_body(
+                Response::new(200).with_header("Content-Type", content_type),
+                &path,
+                &metadata,
+                site,
+            ),
+            RangeRequest::Partial(ranges) if ranges.len() == 1 => with_file_range(
+                Response::new(206)
+                    .with_header("Content-Type", content_type)
+                    .with_header("Content-Range", &ranges[0].content_range(file_len)),
+                &path,
+                &metadata,
+                ranges[0],
+                site,
+            ),
+            RangeRequest::Partial(ranges) => fs::File::open(&path)
+                .map(|file| Response::new(206).with_ranges(file, &ranges, content_type, file_len)),
+            RangeRequest::Unsatisfiable => Ok(Response::new(416)
+                .with_header("Content-Range", &format!("bytes */{file_len}"))),
+        };
+        match response {
+            Ok(response) => response.with_header("Accept-Ranges", "bytes"),
+            Err(err) => {
+                eprintln!("Error reading contents of {}: {}", path.display(), err);
+                return not_found();
+            }
+        }
     };
 
-    let length = contents.len();
+    // A 304 already carries the tag of the variant the client validated
+    let response = match response.status {
+        304 => response,
+        _ => response.with_header("ETag", &etag),
+    };
+    match last_modified {
+        Some(last_modified) => response.with_header("Last-Modified", &last_modified),
+        None => response,
+    }
+}
+
+// Lists a directory without an index.html, at its URL with a trailing slash
+// so the relative links in the listing work
+fn list_directory(request: &Request, dir: &Path) -> Response {
+    let (path, query) = request.path.split_once('?').map_or((request.path.as_str(), String::new()), |(path, query)| {
+        (path, format!("?{query}"))
+    });
+    if !path.ends_with('/') {
+        return redirect(301, &format!("{path}/{query}"));
+    }
 
-    let response = format!("{status_line}\r\nContent-Type: {content_type}\r\nContent-Length: {length}\r\n\r\n{contents}");
+    listing::directory_listing(dir, path).unwrap_or_else(|err| {
+        eprintln!("Error listing {}: {}", dir.display(), err);
+        not_found()
+    })
+}
 
-    if let Err(err) = stream.write_all(response.as_bytes()) {
-        eprintln!("Failed to write response to stream: {}", err);
+impl Handler for Site {
+    fn handle(&self, request: &Request) -> Response {
+        match request.method.as_str() {
+            "GET" | "HEAD" => handle_get(request, self),
+            _ => error_response(405).with_header("Allow", ALLOWED_METHODS),
+        }
     }
 }
 
-fn handle_connection(mut stream: TcpStream) -> Result<(), Box<dyn std::error::Error>> {
-    let buf_reader = BufReader::new(&mut stream);
-    // let request_line = buf_reader.lines().next().unwrap().unwrap();
+// The response to a request, from whichever route takes it
+fn respond(request: &Request, state: &ServerState) -> Response {
+    state.router.handle(request)
+}
 
-    // TODO: print out entire request, handle them appropriately
-    let http_request: Vec<_> = buf_reader
-        .lines()
-        .map(|result| result.unwrap())
-        .take_while(|line| !line.is_empty())
-        .collect();
+// Sends a plain HTTP request made to the HTTPS port over to https. The
+// redirect is temporary so browsers don't remember it once TLS is off again.
+fn https_redirect(request: &Request, local_addr: SocketAddr) -> Response {
+    let host = request
+        .header("Host")
+        .map(str::to_string)
+        .unwrap_or_else(|| local_addr.to_string());
+    error_response(307).with_header("Location", &format!("https://{host}{}", request.path))
+}
 
-    // when debugging, print each request
-    // for line in &http_request {
-    //     println!("{line}");
-    // }
+fn handle_connection(stream: TcpStream, state: &ServerState, shutdown: &Shutdown) -> Result<(), Box<dyn std::error::Error>> {
+    let peer_addr = stream.peer_addr()?;
 
-    // Extract the request line
-    let request_line: Vec<&str> = match http_request.get(0) {
-        Some(line) => {
-            println!("{}: {line}", stream.peer_addr()?);
-            line.split_whitespace().collect()
-        },
-        None => {
-            eprintln!("Failed to read request line");
-            return Err("Failed to read request line".into());
-        }
+    // Idle connections occupy a worker, so don't wait long for the next request
+    stream.set_read_timeout(Some(KEEP_ALIVE_TIMEOUT))?;
+    // Nor let a client that stops reading its response hold on to one
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let Some(tls) = &state.tls else {
+        let mut stream = stream;
+        return serve_requests(&mut stream, peer_addr, state, shutdown, |request| respond(request, state));
     };
 
-    if request_line.len() != 3 {
-        eprintln!("Invalid request line format");
-        return Err("Invalid request line format".into());
+    // Look at the first byte to tell TLS clients from plain HTTP ones
+    let mut first_byte = [0; 1];
+    match stream.peek(&mut first_byte) {
+        Ok(0) => return Ok(()),
+        Ok(_) => {}
+        // Browsers open spare connections they never use
+        Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(()),
+        Err(err) => return Err(err.into()),
+    }
+
+    if !tls::is_handshake(first_byte[0]) {
+        let mut stream = stream;
+        if state.https_redirect {
+            let local_addr = stream.local_addr()?;
+            return serve_requests(&mut stream, peer_addr, state, shutdown, |request| https_redirect(request, local_addr));
+        }
+        // Like nginx, tell the client why its request won't be answered
+        return serve_requests(&mut stream, peer_addr, state, shutdown, |_| error_response(400));
     }
 
-    let (status, path, _version) = (request_line[0], request_line[1], request_line[2]);
+    let mut stream = rustls::StreamOwned::new(tls.accept()?, stream);
+    serve_requests(&mut stream, peer_addr, state, shutdown, |request| respond(request, state))?;
 
-    let path = Path::new("/output").join(path);
+    // Let the client know the connection was closed on purpose
+    stream.conn.send_close_notify();
+    let _ = stream.flush();
+    Ok(())
+}
 
-    let res = match status {
-        "GET" => handle_get(stream, path),
-        _ => {
-            println!("Invalid request was received: {:?}", request_line)
-        },
+// Adds the headers every response gets, and decides whether the connection
+// stays open for another request after this one, the `served`th
+fn finish_response(request: &Request, mut response: Response, served: u32, shutdown: &Shutdown) -> (Response, bool) {
+    // Browsers must trust our Content-Type rather than guess from the body
+    if response.header("X-Content-Type-Options").is_none() {
+        response.set_header("X-Content-Type-Options", "nosniff");
+    }
+
+    // A shutting down server finishes this request but takes no more
+    let keep_alive = request.wants_keep_alive()
+        && served < MAX_REQUESTS_PER_CONNECTION
+        && !shutdown.is_triggered();
+
+    let response = if keep_alive {
+        response
+            .with_header("Connection", "keep-alive")
+            .with_header(
+                "Keep-Alive",
+                &format!(
+                    "timeout={}, max={}",
+                    KEEP_ALIVE_TIMEOUT.as_secs(),
+                    MAX_REQUESTS_PER_CONNECTION - served
+                ),
+            )
+    } else {
+        response.with_header("Connection", "close")
     };
+    (response, keep_alive)
+}
+
+// An error sent before giving up on the connection, when there's no usable
+// request to answer
+fn closing_error_response(status: u16) -> Response {
+    error_response(status)
+        .with_header("X-Content-Type-Options", "nosniff")
+        .with_header("Connection", "close")
+}
+
+// Answers requests on a connection until it closes or stops being kept alive
+fn serve_requests(
+    stream: &mut (impl Read + Write),
+    peer_addr: SocketAddr,
+    state: &ServerState,
+    shutdown: &Shutdown,
+    respond: impl Fn(&Request) -> Response,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(stream);
+
+    for served in 1..=MAX_REQUESTS_PER_CONNECTION {
+        let request = match parse_request(&mut reader) {
+            Ok(request) => request,
+            Err(err) => {
+                // Tell the client what went wrong when there is a status for it,
+                // the connection can't be trusted for another request either way
+                if let Some(status) = err.status() {
+                    eprintln!("{peer_addr}: {err}");
+                    let received = SystemTime::now();
+                    let start = Instant::now();
+                    let sent = closing_error_response(status).write_to(reader.get_mut(), true);
+                    state.record(&Entry {
+                        time: received,
+                        peer: peer_addr,
+                        request: None,
+                        status,
+                        bytes: *sent.as_ref().unwrap_or(&0),
+                        latency: start.elapsed(),
+                    });
+                    sent?;
+                }
+                break;
+            }
+        };
+
+        let received = SystemTime::now();
+        let start = Instant::now();
+        let (response, keep_alive) = finish_response(&request, respond(&request), served, shutdown);
 
-    println!("{:?}", res);
+        let status = response.status;
+        let sent = response.write_to(reader.get_mut(), request.method != "HEAD");
+        state.record(&Entry {
+            time: received,
+            peer: peer_addr,
+            request: Some(&request),
+            status,
+            bytes: *sent.as_ref().unwrap_or(&0),
+            latency: start.elapsed(),
+        });
+        sent?;
+
+        if !keep_alive {
+            break;
+        }
+    }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+
+    // A site with a single page, in a directory of its own per test
+    fn site(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("blog-server-test-{}-{name}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("index.html"), "<p>Hello</p>").unwrap();
+        root
+    }
+
+    // GET / on an open connection, returning the status line, whether the
+    // server keeps the connection open, and the body
+    fn get(reader: &mut BufReader<TcpStream>) -> (String, bool, String) {
+        let (status, headers, body) = get_with(reader, "/", "");
+        let keep_alive = headers
+            .iter()
+            .any(|(name, value)| name.eq_ignore_ascii_case("Connection") && value.eq_ignore_ascii_case("keep-alive"));
+        (status, keep_alive, String::from_utf8(body).unwrap())
+    }
+
+    // GET `path` with `extra_headers`, each ending in "\r\n", returning the
+    // status line, the headers and the body
+    fn get_with(reader: &mut BufReader<TcpStream>, path: &str, extra_headers: &str) -> (String, Vec<(String, String)>, Vec<u8>) {
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n{extra_headers}\r\n");
+        reader.get_mut().write_all(request.as_bytes()).unwrap();
+
+        let mut status = String::new();
+        reader.read_line(&mut status).unwrap();
+        let mut headers = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.to_string(), value.trim().to_string()));
+        }
+
+        let content_length = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+            .map_or(0, |(_, value)| value.parse().unwrap());
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        (status.trim_end().to_string(), headers, body)
+    }
+
+    fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+        headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn connect(addr: SocketAddr) -> BufReader<TcpStream> {
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+        BufReader::new(stream)
+    }
+
+    #[test]
+    fn serves_on_an_ephemeral_port_and_shuts_down() {
+        let root = site("ephemeral");
+        let server = Server::new(Config::default()).with_port(0).with_root(&root).start().unwrap();
+        assert_ne!(server.local_addr().port(), 0);
+
+        let (status, _, body) = get(&mut connect(server.local_addr()));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body, "<p>Hello</p>");
+
+        let start = Instant::now();
+        server.shutdown();
+        assert!(start.elapsed() < SHUTDOWN_TIMEOUT);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn shutdown_closes_idle_keep_alive_connections() {
+        let root = site("keep-alive");
+        let server = Server::new(Config::default()).with_port(0).with_root(&root).start().unwrap();
+        let addr = server.local_addr();
+
+        // Leave a connection open between requests, holding on to its worker
+        let mut reader = connect(addr);
+        let (status, keep_alive, _) = get(&mut reader);
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(keep_alive);
+
+        let start = Instant::now();
+        server.shutdown();
+        assert!(start.elapsed() < SHUTDOWN_TIMEOUT);
+
+        // The server hung up on the idle connection and stopped listening
+        let mut rest = Vec::new();
+        assert_eq!(reader.read_to_end(&mut rest).unwrap_or(0), 0);
+        assert!(TcpStream::connect(addr).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn not_modified_describes_the_compressed_variant() {
+        let root = site("not-modified");
+        fs::write(root.join("styles.css"), "body { color: black; }\n".repeat(100)).unwrap();
+        let server = Server::new(Config::default()).with_port(0).with_root(&root).start().unwrap();
+        let mut reader = connect(server.local_addr());
+
+        let (status, headers, _) = get_with(&mut reader, "/styles.css", "Accept-Encoding: gzip\r\n");
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(header(&headers, "Content-Encoding"), Some("gzip"));
+        let etag = header(&headers, "ETag").unwrap().to_string();
+        assert!(etag.ends_with("-gzip\""));
+
+        let revalidate = format!("Accept-Encoding: gzip\r\nIf-None-Match: {etag}\r\n");
+        let (status, headers, _) = get_with(&mut reader, "/styles.css", &revalidate);
+        assert_eq!(status, "HTTP/1.1 304 Not Modified");
+        assert_eq!(header(&headers, "ETag"), Some(etag.as_str()));
+        assert_eq!(header(&headers, "Vary"), Some("Accept-Encoding"));
+
+        server.shutdown();
+        fs::remove_dir_all(root).unwrap();
+    }
+}
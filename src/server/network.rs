@@ -0,0 +1,50 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+/// `scheme` URLs the server can be reached at when listening on `local_addr`.
+///
+/// A wildcard address (`0.0.0.0` or `::`) is reachable through localhost and
+/// the machine's LAN address as well as any other interface, so those are
+/// listed; the LAN address is the one outgoing traffic would use.
+pub fn reachable_urls(scheme: &str, local_addr: SocketAddr) -> Vec<String> {
+    let port = local_addr.port();
+    let url = |ip: IpAddr| format!("{scheme}://{}", SocketAddr::new(ip, port));
+
+    let ip = local_addr.ip();
+    if !ip.is_unspecified() {
+        return vec![url(ip)];
+    }
+
+    let mut urls = vec![format!("{scheme}://localhost:{port}"), url(IpAddr::V4(Ipv4Addr::LOCALHOST))];
+
+    // Listening on :: also accepts IPv4 connections on dual-stack systems
+    if let Some(lan) = outbound_address(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))) {
+        urls.push(url(lan));
+    }
+    if ip.is_ipv6() {
+        urls.push(url(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+        if let Some(lan) = outbound_address(IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))) {
+            urls.push(url(lan));
+        }
+    }
+
+    urls.dedup();
+    urls
+}
+
+/// Whether the server is reachable from other machines.
+pub fn is_exposed(local_addr: SocketAddr) -> bool {
+    !local_addr.ip().is_loopback()
+}
+
+// Address of the interface that would route to `target`. Connecting a UDP
+// socket only selects a route, no packet is ever sent.
+fn outbound_address(target: IpAddr) -> Option<IpAddr> {
+    let bind_addr: IpAddr = match target {
+        IpAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        IpAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((bind_addr, 0)).ok()?;
+    socket.connect((target, 9)).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_loopback() && !ip.is_unspecified()).then_some(ip)
+}
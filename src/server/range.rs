@@ -0,0 +1,102 @@
+// Requests asking for more ranges than this are served in full instead, so a
+// client can't make us seek around a file thousands of times for one request
+const MAX_RANGES: usize = 16;
+
+// An inclusive byte range within a file
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    pub fn len(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    // Value of the Content-Range header for this range
+    pub fn content_range(&self, file_len: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, file_len)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RangeRequest {
+    /// The header was missing, malformed or not worth honoring, serve the whole file
+    Full,
+    /// One or more satisfiable ranges, in the order the client asked for them
+    Partial(Vec<ByteRange>),
+    /// The header was valid but no range overlaps the file, answer with 416
+    Unsatisfiable,
+}
+
+/// Parse a `Range` header value such as `bytes=0-499, -500, 9500-` against a
+/// file of `file_len` bytes.
+pub fn parse_range(header: &str, file_len: u64) -> RangeRequest {
+    let Some(specs) = header.trim().strip_prefix("bytes=") else {
+        // Only byte ranges exist, other units are ignored as RFC 9110 allows
+        return RangeRequest::Full;
+    };
+
+    let specs: Vec<&str> = specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()).collect();
+    if specs.is_empty() {
+        return RangeRequest::Full;
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs {
+        let Some((first, last)) = spec.split_once('-') else {
+            return RangeRequest::Full;
+        };
+
+        let range = match (first.trim(), last.trim()) {
+            // "-500" => the final 500 bytes
+            ("", suffix) => {
+                let Ok(suffix) = suffix.parse::<u64>() else {
+                    return RangeRequest::Full;
+                };
+                if suffix == 0 || file_len == 0 {
+                    continue;
+                }
+                ByteRange {
+                    start: file_len.saturating_sub(suffix),
+                    end: file_len - 1,
+                }
+            }
+            // "9500-" => from 9500 to the end of the file
+            (start, "") => {
+                let Ok(start) = start.parse::<u64>() else {
+                    return RangeRequest::Full;
+                };
+                if start >= file_len {
+                    continue;
+                }
+                ByteRange { start, end: file_len - 1 }
+            }
+            // "0-499" => the first 500 bytes, the end is clamped to the file
+            (start, end) => {
+                let (Ok(start), Ok(end)) = (start.parse::<u64>(), end.parse::<u64>()) else {
+                    return RangeRequest::Full;
+                };
+                if end < start {
+                    return RangeRequest::Full;
+                }
+                if start >= file_len {
+                    continue;
+                }
+                ByteRange { start, end: end.min(file_len - 1) }
+            }
+        };
+        ranges.push(range);
+    }
+
+    if ranges.len() > MAX_RANGES {
+        return RangeRequest::Full;
+    }
+
+    if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Partial(ranges)
+    }
+}
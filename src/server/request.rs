@@ -0,0 +1,439 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead},
+    time::{Duration, Instant},
+};
+
+// Longest request line we accept, anything longer is answered with 414
+pub const MAX_REQUEST_LINE: usize = 8 * 1024;
+
+// Total size of all header lines, and how many of them there may be
+pub const MAX_HEADER_BYTES: usize = 16 * 1024;
+pub const MAX_HEADERS: usize = 100;
+
+// The dev server never needs a request body, but small ones are read and
+// discarded so the connection stays usable
+pub const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+// Once a request has started it must be fully received within this time
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The request line and headers of an incoming request
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub version: String,
+    // Header names are stored lowercase, repeated headers are joined with ", "
+    pub headers: HashMap<String, String>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(&name.to_ascii_lowercase()).map(String::as_str)
+    }
+
+    // HTTP/1.1 connections persist unless the client says otherwise,
+    // HTTP/1.0 clients have to ask for it
+    pub fn wants_keep_alive(&self) -> bool {
+        let connection_has = |token: &str| {
+            self.header("Connection")
+                .map(|value| value.split(',').any(|part| part.trim().eq_ignore_ascii_case(token)))
+                .unwrap_or(false)
+        };
+
+        match self.version.as_str() {
+            "HTTP/1.1" => !connection_has("close"),
+            _ => connection_has("keep-alive"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    /// The client closed the connection, or went idle, before sending anything
+    Closed,
+    /// The request was malformed
+    BadRequest(&'static str),
+    /// The request target is longer than `MAX_REQUEST_LINE`
+    UriTooLong,
+    /// The headers exceed `MAX_HEADER_BYTES` or `MAX_HEADERS`
+    HeadersTooLarge,
+    /// The declared body exceeds `MAX_BODY_BYTES`
+    PayloadTooLarge,
+    /// The request started but wasn't completed within the read timeouts
+    Timeout,
+    /// A transfer coding we don't implement, such as chunked
+    UnsupportedTransferEncoding,
+    /// Anything other than HTTP/1.x
+    UnsupportedVersion,
+    Io(io::Error),
+}
+
+impl ParseError {
+    /// Status code to answer with, `None` when the connection should just be dropped.
+    pub fn status(&self) -> Option<u16> {
+        match self {
+            ParseError::Closed | ParseError::Io(_) => None,
+            ParseError::BadRequest(_) => Some(400),
+            ParseError::Timeout => Some(408),
+            ParseError::PayloadTooLarge => Some(413),
+            ParseError::UriTooLong => Some(414),
+            ParseError::HeadersTooLarge => Some(431),
+            ParseError::UnsupportedTransferEncoding => Some(501),
+            ParseError::UnsupportedVersion => Some(505),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Closed => write!(f, "Connection closed before a request was sent"),
+            ParseError::BadRequest(reason) => write!(f, "Bad request: {}", reason),
+            ParseError::UriTooLong => write!(f, "Request line exceeds {} bytes", MAX_REQUEST_LINE),
+            ParseError::HeadersTooLarge => write!(f, "Request headers exceed {} bytes or {} fields", MAX_HEADER_BYTES, MAX_HEADERS),
+            ParseError::PayloadTooLarge => write!(f, "Request body exceeds {} bytes", MAX_BODY_BYTES),
+            ParseError::Timeout => write!(f, "Timed out reading request"),
+            ParseError::UnsupportedTransferEncoding => write!(f, "Unsupported transfer encoding"),
+            ParseError::UnsupportedVersion => write!(f, "Unsupported HTTP version"),
+            ParseError::Io(err) => write!(f, "I/O error reading request: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+// When the request must be complete by, set `timeout` after its first byte
+// arrives. Checked after every read, so a client trickling bytes in can't
+// keep resetting the per-read timeout of the stream.
+struct Deadline {
+    timeout: Duration,
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    fn new(timeout: Duration) -> Deadline {
+        Deadline { timeout, at: None }
+    }
+
+    fn started(&self) -> bool {
+        self.at.is_some()
+    }
+
+    fn check(&mut self) -> Result<(), ParseError> {
+        let timeout = self.timeout;
+        let deadline = *self.at.get_or_insert_with(|| Instant::now() + timeout);
+        if Instant::now() > deadline {
+            return Err(ParseError::Timeout);
+        }
+        Ok(())
+    }
+
+    // A read timeout only means the request is late once it has started
+    fn read_error(&self, err: io::Error) -> ParseError {
+        if self.started() && is_timeout(&err) {
+            ParseError::Timeout
+        } else {
+            ParseError::Io(err)
+        }
+    }
+}
+
+// Reads one line, without its line ending, refusing to buffer more than
+// `limit` bytes. Ok(None) means the reader hit EOF before any byte was read.
+fn read_line(
+    reader: &mut impl BufRead,
+    limit: usize,
+    too_long: fn() -> ParseError,
+    deadline: &mut Deadline,
+) -> Result<Option<Vec<u8>>, ParseError> {
+    let mut line = Vec::new();
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(deadline.read_error(err)),
+        };
+
+        if available.is_empty() {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            return Err(ParseError::BadRequest("connection closed mid-line"));
+        }
+        deadline.check()?;
+
+        let (chunk, found_newline) = match available.iter().position(|&byte| byte == b'\n') {
+            Some(index) => (&available[..=index], true),
+            None => (available, false),
+        };
+
+        if line.len() + chunk.len() > limit + 2 {
+            return Err(too_long());
+        }
+        line.extend_from_slice(chunk);
+        let used = chunk.len();
+        reader.consume(used);
+
+        if found_newline {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            return Ok(Some(line));
+        }
+    }
+}
+
+// Reads and drops a body of `length` bytes
+fn discard_body(reader: &mut impl BufRead, length: u64, deadline: &mut Deadline) -> Result<(), ParseError> {
+    let mut remaining = length;
+    while remaining > 0 {
+        let available = match reader.fill_buf() {
+            Ok(available) => available.len(),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(deadline.read_error(err)),
+        };
+        if available == 0 {
+            return Err(ParseError::BadRequest("connection closed before end of body"));
+        }
+        deadline.check()?;
+
+        let used = available.min(remaining as usize);
+        reader.consume(used);
+        remaining -= used as u64;
+    }
+    Ok(())
+}
+
+// Control characters would end up verbatim in headers we echo back, such as
+// `Location` in a redirect, so they are refused wherever the client sends text
+fn has_control(value: &str, allow_tab: bool) -> bool {
+    value
+        .bytes()
+        .any(|byte| byte.is_ascii_control() && !(allow_tab && byte == b'\t'))
+}
+
+// Methods and header names are tokens as defined by RFC 9110
+fn is_token(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte))
+}
+
+/// Read and parse the next request from `reader`.
+///
+/// The caller is expected to have set a read timeout on the underlying
+/// stream. A timeout before the first byte is reported as `Closed` since the
+/// connection was simply idle, a timeout afterwards as `Timeout`.
+pub fn parse_request(reader: &mut impl BufRead) -> Result<Request, ParseError> {
+    parse_request_within(reader, REQUEST_TIMEOUT)
+}
+
+fn parse_request_within(reader: &mut impl BufRead, timeout: Duration) -> Result<Request, ParseError> {
+    // Everything from the first byte on counts against the timeout
+    let mut deadline = Deadline::new(timeout);
+
+    // Skip stray blank lines between requests as RFC 9112 recommends
+    let request_line = loop {
+        match read_line(reader, MAX_REQUEST_LINE, || ParseError::UriTooLong, &mut deadline) {
+            Ok(Some(line)) if line.is_empty() => continue,
+            Ok(Some(line)) => break line,
+            Ok(None) => return Err(ParseError::Closed),
+            Err(ParseError::Io(err)) if is_timeout(&err) => return Err(ParseError::Closed),
+            Err(err) => return Err(err),
+        }
+    };
+
+    let request_line = String::from_utf8(request_line)
+        .map_err(|_| ParseError::BadRequest("request line is not valid UTF-8"))?;
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    let [method, path, version] = parts[..] else {
+        return Err(ParseError::BadRequest("malformed request line"));
+    };
+
+    if !is_token(method) {
+        return Err(ParseError::BadRequest("invalid method"));
+    }
+    if !(path.starts_with('/') || path == "*") || has_control(path, false) {
+        return Err(ParseError::BadRequest("invalid request target"));
+    }
+    if !version.starts_with("HTTP/") {
+        return Err(ParseError::BadRequest("invalid HTTP version"));
+    }
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(ParseError::UnsupportedVersion);
+    }
+
+    let mut headers: HashMap<String, String> = HashMap::new();
+    let mut header_bytes = 0;
+    let mut header_count = 0;
+    loop {
+        let remaining = MAX_HEADER_BYTES.saturating_sub(header_bytes);
+        let line = read_line(reader, remaining, || ParseError::HeadersTooLarge, &mut deadline)?
+            .ok_or(ParseError::BadRequest("connection closed before end of headers"))?;
+
+        if line.is_empty() {
+            break;
+        }
+
+        header_bytes += line.len() + 2;
+        header_count += 1;
+        if header_bytes > MAX_HEADER_BYTES || header_count > MAX_HEADERS {
+            return Err(ParseError::HeadersTooLarge);
+        }
+
+        // Obsolete line folding is rejected, RFC 9112 allows either that or unfolding
+        if line[0] == b' ' || line[0] == b'\t' {
+            return Err(ParseError::BadRequest("folded header lines are not supported"));
+        }
+
+        let line = String::from_utf8(line)
+            .map_err(|_| ParseError::BadRequest("header is not valid UTF-8"))?;
+        let Some((name, value)) = line.split_once(':') else {
+            return Err(ParseError::BadRequest("header without a colon"));
+        };
+        if !is_token(name) {
+            return Err(ParseError::BadRequest("invalid header name"));
+        }
+
+        let name = name.to_ascii_lowercase();
+        let value = value.trim();
+        if has_control(value, true) {
+            return Err(ParseError::BadRequest("control character in header value"));
+        }
+        headers
+            .entry(name)
+            .and_modify(|existing| {
+                existing.push_str(", ");
+                existing.push_str(value);
+            })
+            .or_insert_with(|| value.to_string());
+    }
+
+    if headers.contains_key("transfer-encoding") {
+        return Err(ParseError::UnsupportedTransferEncoding);
+    }
+
+    let content_length = match headers.get("content-length") {
+        None => 0,
+        Some(length) => length
+            .parse::<u64>()
+            .map_err(|_| ParseError::BadRequest("invalid Content-Length"))?,
+    };
+    if content_length > MAX_BODY_BYTES {
+        return Err(ParseError::PayloadTooLarge);
+    }
+
+    discard_body(reader, content_length, &mut deadline)?;
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        version: version.to_string(),
+        headers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, thread};
+
+    fn parse(raw: &[u8]) -> Result<Request, ParseError> {
+        parse_request(&mut &raw[..])
+    }
+
+    fn status(raw: &[u8]) -> Option<u16> {
+        parse(raw).unwrap_err().status()
+    }
+
+    // Hands out one byte per read, sleeping before each of them
+    struct Trickle<'a> {
+        data: &'a [u8],
+        delay: Duration,
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let available = self.fill_buf()?;
+            let n = available.len().min(buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            self.consume(n);
+            Ok(n)
+        }
+    }
+
+    impl BufRead for Trickle<'_> {
+        fn fill_buf(&mut self) -> io::Result<&[u8]> {
+            thread::sleep(self.delay);
+            Ok(&self.data[..self.data.len().min(1)])
+        }
+
+        fn consume(&mut self, amount: usize) {
+            self.data = &self.data[amount..];
+        }
+    }
+
+    #[test]
+    fn parses_a_request() {
+        let request = parse(b"GET /a%20b HTTP/1.1\r\nHost: localhost\r\nAccept: a\r\naccept: b\r\n\r\n").unwrap();
+        assert_eq!(request.method, "GET");
+        assert_eq!(request.path, "/a%20b");
+        assert_eq!(request.header("Host"), Some("localhost"));
+        assert_eq!(request.header("Accept"), Some("a, b"));
+        assert!(request.wants_keep_alive());
+    }
+
+    #[test]
+    fn control_characters_are_rejected() {
+        assert_eq!(status(b"GET /a\rb HTTP/1.1\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET /a\x7fb HTTP/1.1\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost: evil\rLocation: x\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nHost: a\x00b\r\n\r\n"), Some(400));
+
+        // Tabs are fine inside a field value
+        let request = parse(b"GET / HTTP/1.1\r\nX-Note: a\tb\r\n\r\n").unwrap();
+        assert_eq!(request.header("X-Note"), Some("a\tb"));
+    }
+
+    #[test]
+    fn errors_map_to_status_codes() {
+        assert_eq!(status(b"GET /\r\n\r\n"), Some(400));
+        assert_eq!(status(b"GET / HTTP/1.1\r\nContent-Length: nope\r\n\r\n"), Some(400));
+
+        let body = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY_BYTES + 1);
+        assert_eq!(status(body.as_bytes()), Some(413));
+
+        let long = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_REQUEST_LINE));
+        assert_eq!(status(long.as_bytes()), Some(414));
+
+        let many = format!("GET / HTTP/1.1\r\n{}\r\n", "X-A: b\r\n".repeat(MAX_HEADERS + 1));
+        assert_eq!(status(many.as_bytes()), Some(431));
+        let large = format!("GET / HTTP/1.1\r\nX-A: {}\r\n\r\n", "b".repeat(MAX_HEADER_BYTES));
+        assert_eq!(status(large.as_bytes()), Some(431));
+
+        assert_eq!(status(b"GET / HTTP/2.0\r\n\r\n"), Some(505));
+        assert!(matches!(parse(b""), Err(ParseError::Closed)));
+    }
+
+    #[test]
+    fn a_trickling_client_hits_the_deadline() {
+        let raw = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+        let timeout = Duration::from_millis(50);
+
+        // Every single read is quick, only the request as a whole is late
+        let mut slow = Trickle { data: raw, delay: Duration::from_millis(5) };
+        assert!(matches!(parse_request_within(&mut slow, timeout), Err(ParseError::Timeout)));
+
+        let mut fast = Trickle { data: raw, delay: Duration::ZERO };
+        assert!(parse_request_within(&mut fast, timeout).is_ok());
+    }
+}
@@ -0,0 +1,129 @@
+use std::path::{Component, Path, PathBuf};
+
+// What a request path maps to in the output directory
+#[derive(Debug, PartialEq)]
+pub enum Resolved {
+    File(PathBuf),
+    /// A directory without an `index.html`
+    Directory(PathBuf),
+    /// The page exists under a different, canonical URL
+    Redirect(String),
+    NotFound,
+}
+
+/// Map a request path onto a file in `root`.
+///
+/// Pages are reachable as `/slug`, `/slug/` and `/slug.html`, but only one of
+/// those is canonical and the others redirect to it: `/slug/` for pages
+/// written as `slug/index.html` when `pretty_urls` is on, `/slug.html` for
+/// pages written as `slug.html` when it is off. `/index.html` always
+/// redirects to `/`, like any `dir/index.html` redirects to `dir/`.
+pub fn resolve(request_path: &str, root: &Path, pretty_urls: bool) -> Resolved {
+    // Keep the query string for redirects, drop any fragment
+    let request_path = request_path.split('#').next().unwrap_or("/");
+    let (path, query) = match request_path.split_once('?') {
+        Some((path, query)) => (path, format!("?{query}")),
+        None => (request_path, String::new()),
+    };
+    let redirect = |location: String| Resolved::Redirect(location + &query);
+
+    let Some(file_path) = join_within(root, path) else {
+        return Resolved::NotFound;
+    };
+    let trailing_slash = path.ends_with('/');
+    let trimmed = path.trim_end_matches('/');
+
+    if file_path.is_dir() {
+        if !file_path.join("index.html").is_file() {
+            return Resolved::Directory(file_path);
+        }
+        if !trailing_slash {
+            return redirect(format!("{path}/"));
+        }
+        return Resolved::File(file_path.join("index.html"));
+    }
+
+    if file_path.is_file() {
+        if trailing_slash {
+            // "/styles.css/" => "/styles.css"
+            return redirect(trimmed.to_string());
+        }
+        if file_path.file_name().is_some_and(|name| name == "index.html") {
+            // "/sample/index.html" => "/sample/"
+            return redirect(path.trim_end_matches("index.html").to_string());
+        }
+        return Resolved::File(file_path);
+    }
+
+    // Nothing exists at the path itself, try the other spellings of a page
+    if let Some(stem) = trimmed.strip_suffix(".html") {
+        // "/sample.html" => "/sample/" when it was written as a directory
+        let dir_index = join_within(root, stem).map(|dir| dir.join("index.html"));
+        if dir_index.is_some_and(|index| index.is_file()) {
+            return redirect(format!("{stem}/"));
+        }
+        return Resolved::NotFound;
+    }
+
+    if trimmed.is_empty() {
+        return Resolved::NotFound;
+    }
+
+    let html_path = join_within(root, &format!("{trimmed}.html"));
+    match html_path {
+        // "/sample" or "/sample/" => "/sample.html"
+        Some(html_path) if html_path.is_file() => {
+            if pretty_urls {
+                // A hand-written page in pretty mode, serve it where it was asked for
+                Resolved::File(html_path)
+            } else {
+                redirect(format!("{trimmed}.html"))
+            }
+        }
+        _ => Resolved::NotFound,
+    }
+}
+
+// Joins a URL path onto `root`, refusing anything that would escape it.
+// Each segment is percent-decoded first, so "/a%20b.html" finds "a b.html".
+fn join_within(root: &Path, path: &str) -> Option<PathBuf> {
+    let mut joined = root.to_path_buf();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        let segment = percent_decode(segment)?;
+        // An encoded separator or NUL would let one segment act as several
+        if segment.contains(['/', '\\', '\0']) {
+            return None;
+        }
+
+        for component in Path::new(&segment).components() {
+            match component {
+                Component::Normal(part) => joined.push(part),
+                Component::CurDir => {}
+                // Never serve anything outside of the output directory
+                _ => return None,
+            }
+        }
+    }
+    Some(joined)
+}
+
+// "a%20b" => "a b", `None` for a malformed escape or a result that isn't UTF-8
+pub(super) fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = bytes.get(i + 1..i + 3)?;
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
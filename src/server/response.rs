@@ -0,0 +1,301 @@
+use std::{
+    collections::VecDeque,
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::Arc,
+};
+use super::range::ByteRange;
+
+// The body of a response, files are streamed rather than read into memory
+pub enum Body {
+    Empty,
+    Bytes(Vec<u8>),
+    // A range of a file held in the file cache
+    Cached {
+        contents: Arc<[u8]>,
+        range: ByteRange,
+    },
+    File {
+        file: fs::File,
+        range: ByteRange,
+    },
+    // A multipart/byteranges body, each part is preceded by its own headers
+    Multipart {
+        file: fs::File,
+        parts: Vec<(String, ByteRange)>,
+        closing: String,
+    },
+}
+
+impl Body {
+    // Number of bytes the body will occupy on the wire
+    pub fn len(&self) -> u64 {
+        match self {
+            Body::Empty => 0,
+            Body::Bytes(bytes) => bytes.len() as u64,
+            Body::Cached { range, .. } | Body::File { range, .. } => range.len(),
+            Body::Multipart { parts, closing, .. } => {
+                parts
+                    .iter()
+                    .map(|(head, range)| head.len() as u64 + range.len())
+                    .sum::<u64>()
+                    + closing.len() as u64
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Read the body piece by piece, for writers that can't block until a
+    // whole file has been sent
+    pub fn into_reader(self) -> BodyReader {
+        let (file, parts) = match self {
+            Body::Empty => (None, VecDeque::new()),
+            Body::Bytes(bytes) => (None, VecDeque::from([Part::Bytes(bytes, 0)])),
+            Body::Cached { contents, range } => (None, VecDeque::from([Part::Cached(contents, range.start, range.end + 1)])),
+            Body::File { file, range } => (Some(file), VecDeque::from([Part::File(range.start, range.end + 1)])),
+            Body::Multipart { file, parts, closing } => {
+                let mut pieces = VecDeque::new();
+                for (head, range) in parts {
+                    pieces.push_back(Part::Bytes(head.into_bytes(), 0));
+                    pieces.push_back(Part::File(range.start, range.end + 1));
+                }
+                pieces.push_back(Part::Bytes(closing.into_bytes(), 0));
+                (Some(file), pieces)
+            }
+        };
+        BodyReader { file, parts }
+    }
+
+    // Read the whole body into memory
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.len() as usize);
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    fn write_to(self, stream: &mut impl Write) -> io::Result<()> {
+        match self {
+            Body::Empty => Ok(()),
+            Body::Bytes(bytes) => stream.write_all(&bytes),
+            Body::Cached { contents, range } => {
+                stream.write_all(&contents[range.start as usize..=range.end as usize])
+            }
+            Body::File { mut file, range } => copy_range(&mut file, range, stream),
+            Body::Multipart { mut file, parts, closing } => {
+                for (head, range) in parts {
+                    stream.write_all(head.as_bytes())?;
+                    copy_range(&mut file, range, stream)?;
+                }
+                stream.write_all(closing.as_bytes())
+            }
+        }
+    }
+}
+
+/// A body being read out in pieces, see `Body::into_reader`.
+pub struct BodyReader {
+    file: Option<fs::File>,
+    parts: VecDeque<Part>,
+}
+
+// What is left of each piece of a body, as the next and the end offset
+enum Part {
+    Bytes(Vec<u8>, usize),
+    Cached(Arc<[u8]>, u64, u64),
+    File(u64, u64),
+}
+
+impl Read for BodyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while let Some(part) = self.parts.front_mut() {
+            let read = match part {
+                Part::Bytes(bytes, next) => {
+                    let read = (&bytes[*next..]).read(buf)?;
+                    *next += read;
+                    read
+                }
+                Part::Cached(contents, next, end) => {
+                    let read = (&contents[*next as usize..*end as usize]).read(buf)?;
+                    *next += read as u64;
+                    read
+                }
+                Part::File(next, end) => match &mut self.file {
+                    Some(file) if next < end => {
+                        let wanted = buf.len().min((*end - *next) as usize);
+                        file.seek(SeekFrom::Start(*next))?;
+                        let read = file.read(&mut buf[..wanted])?;
+                        if read == 0 {
+                            // The file shrank underneath us, the Content-Length we sent is now a lie
+                            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated while sending"));
+                        }
+                        *next += read as u64;
+                        read
+                    }
+                    _ => 0,
+                },
+            };
+
+            if read > 0 {
+                return Ok(read);
+            }
+            self.parts.pop_front();
+        }
+        Ok(0)
+    }
+}
+
+fn copy_range(file: &mut fs::File, range: ByteRange, stream: &mut impl Write) -> io::Result<()> {
+    file.seek(SeekFrom::Start(range.start))?;
+    let copied = io::copy(&mut file.take(range.len()), stream)?;
+    if copied != range.len() {
+        // The file shrank underneath us, the Content-Length we sent is now a lie
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file truncated while sending"));
+    }
+    Ok(())
+}
+
+// An HTTP response, written to the stream once the handler is done with it
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Body::Empty,
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Response {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Replace every existing value of a header with `value`
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    pub fn with_body(mut self, body: Vec<u8>) -> Response {
+        self.body = Body::Bytes(body);
+        self
+    }
+
+    // Stream `range` of `file` as the body
+    pub fn with_file(mut self, file: fs::File, range: ByteRange) -> Response {
+        self.body = Body::File { file, range };
+        self
+    }
+
+    // Send `range` of a cached file as the body
+    pub fn with_cached(mut self, contents: Arc<[u8]>, range: ByteRange) -> Response {
+        self.body = Body::Cached { contents, range };
+        self
+    }
+
+    // Stream several ranges of `file` as a multipart/byteranges body
+    pub fn with_ranges(mut self, file: fs::File, ranges: &[ByteRange], content_type: &str, file_len: u64) -> Response {
+        let boundary = multipart_boundary(file_len);
+        let parts = ranges
+            .iter()
+            .map(|range| {
+                let head = format!(
+                    "\r\n--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: {}\r\n\r\n",
+                    range.content_range(file_len)
+                );
+                (head, *range)
+            })
+            .collect();
+
+        self.body = Body::Multipart {
+            file,
+            parts,
+            closing: format!("\r\n--{boundary}--\r\n"),
+        };
+        self.with_header("Content-Type", &format!("multipart/byteranges; boundary={boundary}"))
+    }
+
+    /// Write the status line, headers and body to `stream`.
+    ///
+    /// When `include_body` is false (HEAD requests) the headers still describe
+    /// the body that a GET would have returned, but the body itself is skipped.
+    /// Returns the number of body bytes written.
+    pub fn write_to(self, stream: &mut impl Write, include_body: bool) -> io::Result<u64> {
+        let (head, body) = self.into_parts(include_body);
+        stream.write_all(&head)?;
+        let sent = body.len();
+        body.write_to(stream)?;
+        stream.flush()?;
+        Ok(sent)
+    }
+
+    /// The status line and headers, and the body to send after them, as
+    /// `write_to` would send them.
+    pub fn into_parts(self, include_body: bool) -> (Vec<u8>, Body) {
+        let mut head = format!("HTTP/1.1 {} {}\r\n", self.status, reason_phrase(self.status));
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        // 304 responses must not carry a Content-Length describing an empty body
+        if self.status != 304 {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+
+        let body = if include_body && self.status != 304 { self.body } else { Body::Empty };
+        (head.into_bytes(), body)
+    }
+}
+
+// Boundaries only need to be absent from the parts, which are file contents
+// we can't predict, so mix the clock into the string
+fn multipart_boundary(seed: u64) -> String {
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or(0);
+    format!("blog-byteranges-{:x}{:x}", nanos, seed)
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
@@ -0,0 +1,140 @@
+use super::{error_response, not_found, request::Request, response::Response};
+
+/// Something that answers requests: the site itself, the status endpoint, or
+/// anything registered with `Server::with_route`.
+///
+/// Closures taking a `&Request` and returning a `Response` are handlers too.
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &Request) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request) -> Response {
+        self(request)
+    }
+}
+
+/// Wraps every request the router answers, e.g. to add headers or compress
+/// the response. Call `next.run` to get the response of whatever comes after.
+///
+/// Closures taking a `&Request` and a `Next` are middleware too.
+pub trait Middleware: Send + Sync {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Response;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&Request, Next<'_>) -> Response + Send + Sync,
+{
+    fn handle(&self, request: &Request, next: Next<'_>) -> Response {
+        self(request, next)
+    }
+}
+
+/// The rest of the middleware chain, ending with the matching route.
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    router: &'a Router,
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &Request) -> Response {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.handle(
+                request,
+                Next {
+                    middleware: rest,
+                    router: self.router,
+                },
+            ),
+            None => self.router.dispatch(request),
+        }
+    }
+}
+
+struct Route {
+    methods: Vec<String>,
+    prefix: String,
+    handler: Box<dyn Handler>,
+}
+
+// Sends each request to the route with the longest path prefix matching it,
+// or to the fallback when none does, through the middleware in the order it
+// was added
+#[derive(Default)]
+pub(super) struct Router {
+    routes: Vec<Route>,
+    fallback: Option<Box<dyn Handler>>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Router {
+    // Answers requests no route matches
+    pub(super) fn set_fallback(&mut self, fallback: Box<dyn Handler>) {
+        self.fallback = Some(fallback);
+    }
+
+    // Answer requests for `prefix` and the paths below it with `handler`. A
+    // route taking GET takes HEAD as well.
+    pub(super) fn add_route(&mut self, methods: &[&str], prefix: &str, handler: Box<dyn Handler>) {
+        let mut methods: Vec<String> = methods.iter().map(|method| method.to_ascii_uppercase()).collect();
+        if methods.iter().any(|method| method == "GET") && !methods.iter().any(|method| method == "HEAD") {
+            methods.push("HEAD".to_string());
+        }
+
+        self.routes.push(Route {
+            methods,
+            prefix: prefix.trim_end_matches('/').to_string(),
+            handler,
+        });
+    }
+
+    pub(super) fn add_middleware(&mut self, middleware: Box<dyn Middleware>) {
+        self.middleware.push(middleware);
+    }
+
+    // Put `middleware` in front of everything added so far
+    pub(super) fn wrap(&mut self, middleware: Box<dyn Middleware>) {
+        self.middleware.insert(0, middleware);
+    }
+
+    pub(super) fn handle(&self, request: &Request) -> Response {
+        Next {
+            middleware: &self.middleware,
+            router: self,
+        }
+        .run(request)
+    }
+
+    fn dispatch(&self, request: &Request) -> Response {
+        let path = request.path.split('?').next().unwrap_or("/");
+        let Some(route) = self
+            .routes
+            .iter()
+            .filter(|route| matches_prefix(path, &route.prefix))
+            .max_by_key(|route| route.prefix.len())
+        else {
+            return match &self.fallback {
+                Some(fallback) => fallback.handle(request),
+                None => not_found(),
+            };
+        };
+
+        if route.methods.contains(&request.method) {
+            route.handler.handle(request)
+        } else {
+            error_response(405).with_header("Allow", &route.methods.join(", "))
+        }
+    }
+}
+
+// "/api" matches "/api" and "/api/search", but not "/apiary"
+fn matches_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.is_empty(),
+        None => false,
+    }
+}
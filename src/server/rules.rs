@@ -0,0 +1,96 @@
+use std::collections::BTreeMap;
+use crate::config::Redirect;
+use super::{
+    request::Request,
+    response::Response,
+    router::{Middleware, Next},
+};
+
+/// Middleware adding the headers configured in `blog.json` to every response.
+pub struct CustomHeaders(BTreeMap<String, BTreeMap<String, String>>);
+
+impl CustomHeaders {
+    pub fn new(headers: BTreeMap<String, BTreeMap<String, String>>) -> CustomHeaders {
+        CustomHeaders(headers)
+    }
+}
+
+impl Middleware for CustomHeaders {
+    fn handle(&self, request: &Request, next: Next<'_>) -> Response {
+        let mut response = next.run(request);
+        let path = request.path.split('?').next().unwrap_or("/");
+        for (name, value) in custom_headers(&self.0, path) {
+            response.set_header(name, value);
+        }
+        response
+    }
+}
+
+/// Headers configured for every glob in `headers` that matches `path`.
+///
+/// Globs are applied in order, so a more specific glob sorting after a
+/// broader one overrides it, the same way Netlify merges `_headers` rules.
+pub fn custom_headers<'a>(
+    headers: &'a BTreeMap<String, BTreeMap<String, String>>,
+    path: &str,
+) -> Vec<(&'a str, &'a str)> {
+    headers
+        .iter()
+        .filter(|(glob, _)| glob_matches(glob, path))
+        .flat_map(|(_, headers)| headers.iter())
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect()
+}
+
+/// The first rule in `redirects` matching `request_path`, and the URL it
+/// points to. The query string is passed on unless the target has its own.
+pub fn find_redirect<'a>(redirects: &'a [Redirect], request_path: &str) -> Option<(&'a Redirect, String)> {
+    let (path, query) = request_path.split_once('?').unwrap_or((request_path, ""));
+    let (rule, target) = redirects
+        .iter()
+        .find_map(|rule| redirect_target(rule, path).map(|target| (rule, target)))?;
+
+    if query.is_empty() || target.contains('?') {
+        return Some((rule, target));
+    }
+    Some((rule, format!("{target}?{query}")))
+}
+
+// "/blog/*" => "/posts/:splat" sends "/blog/a/b" to "/posts/a/b", other rules
+// match their path exactly, ignoring a trailing slash
+fn redirect_target(rule: &Redirect, path: &str) -> Option<String> {
+    match rule.from.strip_suffix('*') {
+        Some(prefix) => path
+            .strip_prefix(prefix)
+            .map(|splat| rule.to.replace(":splat", splat)),
+        None => (rule.from.trim_end_matches('/') == path.trim_end_matches('/')).then(|| rule.to.clone()),
+    }
+}
+
+// Wildcard match where `*` stands for any run of characters, slashes included
+fn glob_matches(glob: &str, path: &str) -> bool {
+    let glob = glob.as_bytes();
+    let path = path.as_bytes();
+    let (mut g, mut p) = (0, 0);
+    // Where the last `*` was seen and how much of the path it has swallowed
+    let mut backtrack = None;
+
+    while p < path.len() {
+        if g < glob.len() && glob[g] == b'*' {
+            backtrack = Some((g, p));
+            g += 1;
+        } else if g < glob.len() && glob[g] == path[p] {
+            g += 1;
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` take one more character and try again
+            g = star + 1;
+            p = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    glob[g..].iter().all(|&byte| byte == b'*')
+}
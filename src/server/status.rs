@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, PoisonError,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use super::file_cache::FileCache;
+use super::request::Request;
+use super::response::Response;
+use super::router::Handler;
+use super::threadpool::Monitor;
+
+/// Path of the status endpoint, when it is turned on.
+pub const STATUS_PATH: &str = "/__blog/status";
+
+/// How the last site build went, reported to a running server with
+/// `ServerHandle::report_build`.
+#[derive(Debug, Clone)]
+pub struct BuildReport {
+    pub finished: SystemTime,
+    pub duration: Duration,
+    /// Why the build failed, `None` when it succeeded
+    pub error: Option<String>,
+}
+
+// Answers `STATUS_PATH`
+pub(super) struct StatusHandler {
+    status: Arc<Status>,
+    pool: Monitor,
+    file_cache: Option<Arc<FileCache>>,
+}
+
+impl StatusHandler {
+    pub(super) fn new(status: Arc<Status>, pool: Monitor, file_cache: Option<Arc<FileCache>>) -> StatusHandler {
+        StatusHandler { status, pool, file_cache }
+    }
+}
+
+impl Handler for StatusHandler {
+    fn handle(&self, _request: &Request) -> Response {
+        self.status.response(&self.pool, self.file_cache.as_deref())
+    }
+}
+
+// Counters behind the status endpoint, updated with every access log entry
+pub(super) struct Status {
+    started: Instant,
+    requests: Mutex<BTreeMap<u16, u64>>,
+    bytes_sent: AtomicU64,
+    last_build: Mutex<Option<BuildReport>>,
+}
+
+impl Status {
+    pub(super) fn new() -> Status {
+        Status {
+            started: Instant::now(),
+            requests: Mutex::new(BTreeMap::new()),
+            bytes_sent: AtomicU64::new(0),
+            last_build: Mutex::new(None),
+        }
+    }
+
+    pub(super) fn record_response(&self, status: u16, bytes: u64) {
+        *self.lock_requests().entry(status).or_insert(0) += 1;
+        self.bytes_sent.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub(super) fn record_build(&self, report: BuildReport) {
+        *self.last_build.lock().unwrap_or_else(PoisonError::into_inner) = Some(report);
+    }
+
+    // {"uptime_secs": 61.2, "requests": {"total": 3, "by_status": {"200": 3}}, ...}
+    pub(super) fn response(&self, pool: &Monitor, file_cache: Option<&FileCache>) -> Response {
+        let by_status = self.lock_requests().clone();
+        let pool = pool.metrics();
+        let last_build = self
+            .last_build
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .map(|build| {
+                json!({
+                    "finished_at": unix_secs(build.finished),
+                    "duration_ms": millis(build.duration),
+                    "ok": build.error.is_none(),
+                    "error": build.error,
+                })
+            });
+
+        let file_cache = file_cache.map(|cache| {
+            let stats = cache.stats();
+            json!({
+                "entries": stats.entries,
+                "bytes": stats.bytes,
+                "max_bytes": stats.max_bytes,
+                "hits": stats.hits,
+                "misses": stats.misses,
+            })
+        });
+
+        let body = json!({
+            "uptime_secs": self.started.elapsed().as_secs_f64(),
+            "requests": {
+                "total": by_status.values().sum::<u64>(),
+                "by_status": by_status,
+            },
+            "bytes_sent": self.bytes_sent.load(Ordering::SeqCst),
+            "pool": {
+                "workers": pool.workers,
+                "active": pool.active,
+                "queued": pool.queued,
+                "completed": pool.completed,
+                "panicked": pool.panicked,
+                "average_job_ms": millis(pool.average_job_time),
+            },
+            "file_cache": file_cache,
+            "last_build": last_build,
+        });
+
+        Response::new(200)
+            .with_header("Content-Type", "application/json")
+            // Pollers always want the current numbers
+            .with_header("Cache-Control", "no-store")
+            .with_body(body.to_string().into_bytes())
+    }
+
+    fn lock_requests(&self) -> std::sync::MutexGuard<'_, BTreeMap<u16, u64>> {
+        self.requests.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs())
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,32 @@
+use std::sync::mpsc;
+use std::thread;
+
+/// The result of a job queued with `ThreadPool::submit`.
+///
+/// Dropping the handle doesn't cancel the job, its result is discarded.
+pub struct JobHandle<T> {
+    receiver: mpsc::Receiver<thread::Result<T>>,
+}
+
+// Sends a job's result, or its panic, to its handle
+pub(super) type ResultSender<T> = mpsc::Sender<thread::Result<T>>;
+
+impl<T> JobHandle<T> {
+    pub(super) fn new() -> (ResultSender<T>, JobHandle<T>) {
+        let (sender, receiver) = mpsc::channel();
+        (sender, JobHandle { receiver })
+    }
+
+    /// Wait for the job to finish. Like `std::thread::JoinHandle::join`, a
+    /// panicking job gives an `Err` holding the panic payload.
+    pub fn join(self) -> thread::Result<T> {
+        self.receiver
+            .recv()
+            .unwrap_or_else(|_| Err(Box::new("the job was dropped without running")))
+    }
+
+    // The result if it's already there, for handles dropped without a join
+    pub(super) fn try_result(&self) -> Option<thread::Result<T>> {
+        self.receiver.try_recv().ok()
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use super::Shared;
+
+/// A snapshot of what a `ThreadPool` is doing, see `ThreadPool::metrics`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metrics {
+    /// Jobs waiting for a worker
+    pub queued: usize,
+    /// Worker threads running, busy or not
+    pub workers: u32,
+    /// Workers running a job right now
+    pub active: usize,
+    /// Jobs that have finished, including ones that panicked
+    pub completed: u64,
+    /// Jobs that panicked
+    pub panicked: usize,
+    /// Mean time a finished job took to run, zero before the first one
+    pub average_job_time: Duration,
+}
+
+// Updated by the workers around every job
+#[derive(Default)]
+pub(super) struct Counters {
+    active: AtomicUsize,
+    completed: AtomicU64,
+    busy_nanos: AtomicU64,
+}
+
+impl Counters {
+    pub(super) fn job_started(&self) {
+        self.active.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(super) fn job_finished(&self, took: Duration) {
+        let nanos = took.as_nanos().try_into().unwrap_or(u64::MAX);
+        self.busy_nanos.fetch_add(nanos, Ordering::SeqCst);
+        self.completed.fetch_add(1, Ordering::SeqCst);
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Reads the metrics of a pool from anywhere, without keeping it from
+/// shutting down. Created with `ThreadPool::monitor`.
+#[derive(Clone)]
+pub struct Monitor {
+    pub(super) shared: Arc<Shared>,
+}
+
+impl Monitor {
+    pub fn metrics(&self) -> Metrics {
+        let shared = &self.shared;
+        let completed = shared.counters.completed.load(Ordering::SeqCst);
+        let busy = Duration::from_nanos(shared.counters.busy_nanos.load(Ordering::SeqCst));
+        let average_job_time = match u32::try_from(completed) {
+            Ok(0) => Duration::ZERO,
+            Ok(completed) => busy / completed,
+            Err(_) => Duration::from_secs_f64(busy.as_secs_f64() / completed as f64),
+        };
+
+        Metrics {
+            queued: shared.queue.len(),
+            workers: shared.live_workers.load(Ordering::SeqCst),
+            active: shared.counters.active.load(Ordering::SeqCst),
+            completed,
+            panicked: shared.panicked_jobs.load(Ordering::SeqCst),
+            average_job_time,
+        }
+    }
+}
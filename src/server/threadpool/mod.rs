@@ -1,91 +1,380 @@
+mod handle;
+mod metrics;
+mod queue;
+mod scope;
 mod worker;
 
-use std::sync::{mpsc, Arc, Mutex};
+use std::panic::{self, AssertUnwindSafe};
+use std::io;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering},
+    Arc, Mutex, PoisonError,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+use metrics::Counters;
+use queue::{JobQueue, PushError, Wait};
 use worker::Worker;
+
+pub use handle::JobHandle;
+pub use metrics::{Metrics, Monitor};
+pub use scope::{Scope, ScopedJobHandle};
+
+// Jobs that may wait for a worker in pools created without a capacity
+pub const DEFAULT_QUEUE_CAPACITY: usize = 256;
+
+// How long a worker above the minimum may wait for a job before it exits
+const WORKER_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often `shutdown` checks whether the workers have finished
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// One worker per available CPU, or 4 when that can't be determined.
+pub fn default_size() -> u32 {
+    thread::available_parallelism()
+        .map(|cpus| cpus.get().try_into().unwrap_or(u32::MAX))
+        .unwrap_or(4)
+}
+
 pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    shared: Arc<Shared>,
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+// State every worker has access to
+struct Shared {
+    queue: JobQueue,
+    // Workers are registered here so replacements for dead ones get joined too
+    workers: Mutex<Vec<Worker>>,
+    panicked_jobs: AtomicUsize,
+    counters: Counters,
+    shutting_down: AtomicBool,
+    min_workers: u32,
+    max_workers: u32,
+    // How long a worker above the minimum may wait for a job before it exits
+    idle_timeout: Duration,
+    // Running workers, including ones that are about to start
+    live_workers: AtomicU32,
+    next_id: AtomicU32,
+}
+
 impl ThreadPool {
     /// Create a new ThreadPool.
     ///
-    /// The size is the number of threads in the pool.
+    /// The size is the number of threads in the pool, up to
+    /// `DEFAULT_QUEUE_CAPACITY` jobs can wait for one of them.
     ///
     /// # Panics
     ///
-    /// The `new` function will panic if the size is zero.
+    /// The `new` function will panic if the size is zero, or if the worker
+    /// threads can't be started.
     pub fn new(size: u32) -> ThreadPool {
         assert!(size > 0);
 
-        let (sender, receiver) = mpsc::channel();
-
-        let receiver = Arc::new(Mutex::new(receiver));
-
-        let mut workers = Vec::with_capacity(size.try_into().unwrap());
-
-        for id in 0..size {
-            // create some threads and store them in the vector
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
-
-        ThreadPool {
-            workers,
-            sender: Some(sender),
-        }
+        ThreadPool::start(size, size, DEFAULT_QUEUE_CAPACITY, WORKER_IDLE_TIMEOUT).expect("failed to start worker threads")
     }
 
     // Version of new with error handling
     pub fn build(size: u32) -> Result<ThreadPool, PoolCreationError> {
-        if size == 0 {
+        ThreadPool::build_bounded(size, DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// Like `build`, with room for `capacity` jobs waiting for a worker.
+    pub fn build_bounded(size: u32, capacity: usize) -> Result<ThreadPool, PoolCreationError> {
+        ThreadPool::build_elastic(size, size, capacity)
+    }
+
+    /// A pool that starts with `min` workers and adds more, up to `max`,
+    /// while jobs are waiting for one. Workers above `min` exit again after
+    /// sitting idle for a while.
+    pub fn build_elastic(min: u32, max: u32, capacity: usize) -> Result<ThreadPool, PoolCreationError> {
+        if max == 0 {
             return Err(PoolCreationError::InvalidSize);
         }
+        if min > max {
+            return Err(PoolCreationError::InvalidBounds);
+        }
+        if capacity == 0 {
+            return Err(PoolCreationError::InvalidCapacity);
+        }
 
-        let (sender, receiver) = mpsc::channel();
-
-        let receiver = Arc::new(Mutex::new(receiver));
+        ThreadPool::start(min, max, capacity, WORKER_IDLE_TIMEOUT)
+    }
 
-        let mut workers = Vec::with_capacity(size.try_into().unwrap());
+    // Tests pass a short `idle_timeout` to see the pool shrink
+    fn start(min: u32, max: u32, capacity: usize, idle_timeout: Duration) -> Result<ThreadPool, PoolCreationError> {
+        let shared = Arc::new(Shared {
+            queue: JobQueue::new(capacity),
+            // Sized for the workers started now, `max` may be far more than
+            // will ever run
+            workers: Mutex::new(Vec::with_capacity(min as usize)),
+            panicked_jobs: AtomicUsize::new(0),
+            counters: Counters::default(),
+            shutting_down: AtomicBool::new(false),
+            min_workers: min,
+            max_workers: max,
+            idle_timeout,
+            live_workers: AtomicU32::new(min),
+            next_id: AtomicU32::new(min),
+        });
+        // Dropping the pool on an error stops the workers already started
+        let pool = ThreadPool { shared };
 
-        for id in 0..size {
+        for id in 0..min {
             // create some threads and store them in the vector
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            let worker = Worker::new(id, Arc::clone(&pool.shared)).map_err(PoolCreationError::Spawn)?;
+            pool.shared.lock_workers().push(worker);
         }
 
-        Ok(ThreadPool {
-            workers,
-            sender: Some(sender),
-        })
+        Ok(pool)
+    }
+
+    /// Number of worker threads currently running.
+    pub fn size(&self) -> u32 {
+        self.shared.live_workers.load(Ordering::SeqCst)
     }
 
+    /// Queue `f`, blocking while the queue is full.
     pub fn execute<F>(&self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        self.push_job(Box::new(f));
+    }
+
+    /// Queue `f` if there is room for it right now.
+    pub fn try_execute<F>(&self, f: F) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.push(Box::new(f), Wait::Never)
+    }
 
-        self.sender.as_ref().unwrap().send(job).unwrap();
+    /// Queue `f`, waiting up to `timeout` for room in a full queue.
+    pub fn execute_timeout<F>(&self, f: F, timeout: Duration) -> Result<(), ExecuteError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.push(Box::new(f), Wait::Until(Instant::now() + timeout))
     }
-}
 
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        drop(self.sender.take());
-        for worker in &mut self.workers {
+    /// Queue `f`, blocking while the queue is full, and get a handle to
+    /// wait for its result.
+    ///
+    /// # Examples
+    /// ```
+    /// use blog::server::threadpool::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let handles: Vec<_> = (1..=3).map(|n| pool.submit(move || n * 10)).collect();
+    /// let results: Vec<u32> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+    /// assert_eq!(results, [10, 20, 30]);
+    /// ```
+    pub fn submit<F, T>(&self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, handle) = JobHandle::new();
+        let shared = Arc::clone(&self.shared);
+        self.push_job(Box::new(move || {
+            // Nobody is waiting for the result if the handle was dropped
+            let _ = sender.send(run_job(&shared, f));
+        }));
+        handle
+    }
+
+    /// Run `f` with a `Scope` whose jobs may borrow from the caller's stack.
+    /// Returns once every job submitted through the scope has finished.
+    ///
+    /// A job that panics hands the panic to whoever joins its handle. If a
+    /// panicking job's handle is never joined, `scope` panics instead, like
+    /// `std::thread::scope`.
+    ///
+    /// Don't call this from a job running on the same pool, the scope could
+    /// end up waiting on jobs stuck behind itself.
+    ///
+    /// # Examples
+    /// ```
+    /// use blog::server::threadpool::ThreadPool;
+    ///
+    /// let pool = ThreadPool::new(2);
+    /// let pages = vec!["a".to_string(), "bb".to_string()];
+    /// let lengths: Vec<usize> = pool.scope(|scope| {
+    ///     let handles: Vec<_> = pages.iter().map(|page| scope.submit(|| page.len())).collect();
+    ///     handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    /// });
+    /// assert_eq!(lengths, [1, 2]);
+    /// ```
+    pub fn scope<'env, F, R>(&'env self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope::new(self);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+
+        // The jobs borrow from our caller, they must be done before we return
+        scope.wait();
+
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.had_unobserved_panic() => panic!("a job submitted to the scope panicked"),
+            Ok(value) => value,
+        }
+    }
+
+    // Queue an already boxed job, blocking while the queue is full
+    fn push_job(&self, job: Job) {
+        // The queue is only closed by dropping the pool, which can't happen
+        // while we're borrowing it
+        let _ = self.push(job, Wait::Forever);
+    }
+
+    fn push(&self, job: Job, wait: Wait) -> Result<(), ExecuteError> {
+        let short_of_workers = self.shared.queue.push(job, wait).map_err(|err| match err {
+            PushError::Full => ExecuteError::Full,
+            PushError::Timeout => ExecuteError::Timeout,
+            PushError::Closed => ExecuteError::ShutDown,
+        })?;
+
+        if short_of_workers {
+            self.grow();
+        }
+        Ok(())
+    }
+
+    // Start another worker unless the pool is already at its maximum
+    fn grow(&self) {
+        let shared = &self.shared;
+        let reserved = shared
+            .live_workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| (live < shared.max_workers).then_some(live + 1));
+        if reserved.is_err() {
+            return;
+        }
+
+        let id = shared.next_id.fetch_add(1, Ordering::SeqCst);
+        match Worker::new(id, Arc::clone(shared)) {
+            Ok(worker) => shared.lock_workers().push(worker),
+            Err(err) => {
+                eprintln!("Failed to start worker {id}: {err}");
+                shared.live_workers.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Number of jobs that panicked. The panic is contained to the job, the
+    /// worker that ran it carries on with the next one.
+    pub fn panicked_jobs(&self) -> usize {
+        self.shared.panicked_jobs.load(Ordering::SeqCst)
+    }
+
+    /// What the pool is doing right now.
+    pub fn metrics(&self) -> Metrics {
+        self.monitor().metrics()
+    }
+
+    /// A handle for reading the metrics from other threads.
+    pub fn monitor(&self) -> Monitor {
+        Monitor {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    /// Stop taking jobs and wait up to `timeout` for the workers to finish
+    /// the ones already queued. Workers still busy after that are left
+    /// running in the background and reported in the error.
+    ///
+    /// Dropping the pool does the same without a time limit.
+    pub fn shutdown(self, timeout: Duration) -> Result<(), ShutdownTimeout> {
+        let unfinished = self.stop(Some(Instant::now() + timeout));
+        if unfinished.is_empty() {
+            Ok(())
+        } else {
+            Err(ShutdownTimeout { timeout, unfinished })
+        }
+    }
+
+    // Close the queue and join the workers, giving up on the ones that are
+    // still running at `deadline`. Returns their ids.
+    fn stop(&self, deadline: Option<Instant>) -> Vec<u32> {
+        self.shared.shutting_down.store(true, Ordering::SeqCst);
+        self.shared.queue.close();
+
+        // Don't hold the lock while joining, a worker that died just before
+        // shutdown may still be registering its replacement
+        let mut workers = std::mem::take(&mut *self.shared.lock_workers());
+        for worker in &workers {
             println!("Shutting down worker {}", worker.id);
+        }
+
+        loop {
+            // Join whoever is done, a worker without a thread never started
+            workers.retain_mut(|worker| match worker.thread.take() {
+                Some(thread) if deadline.is_none() || thread.is_finished() => {
+                    if thread.join().is_err() {
+                        eprintln!("Worker {} panicked while shutting down", worker.id);
+                    }
+                    false
+                }
+                Some(thread) => {
+                    worker.thread = Some(thread);
+                    true
+                }
+                None => false,
+            });
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            match deadline {
+                Some(deadline) if !workers.is_empty() && Instant::now() < deadline => {
+                    thread::sleep(SHUTDOWN_POLL_INTERVAL);
+                }
+                // Dropping the rest detaches their threads
+                _ => return workers.iter().map(|worker| worker.id).collect(),
             }
         }
     }
 }
 
+// Runs a job whose result has somewhere to go, counting its panic the same
+// way the worker counts those of jobs without a result
+fn run_job<F, T>(shared: &Shared, f: F) -> thread::Result<T>
+where
+    F: FnOnce() -> T,
+{
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    if result.is_err() {
+        shared.panicked_jobs.fetch_add(1, Ordering::SeqCst);
+    }
+    result
+}
+
+impl Shared {
+    fn lock_workers(&self) -> std::sync::MutexGuard<'_, Vec<Worker>> {
+        self.workers.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    // Whether an idle worker may exit, claiming its place if so
+    fn retire_idle_worker(&self) -> bool {
+        self.live_workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |live| (live > self.min_workers).then(|| live - 1))
+            .is_ok()
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // Nothing is left to join after an explicit shutdown
+        self.stop(None);
+    }
+}
+
 #[derive(Debug)]
 pub enum PoolCreationError {
     InvalidSize,
+    InvalidBounds,
+    InvalidCapacity,
+    Spawn(io::Error),
 }
 
 use std::fmt;
@@ -94,6 +383,256 @@ impl fmt::Display for PoolCreationError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             PoolCreationError::InvalidSize => write!(f, "The pool size must be greater than 0"),
+            PoolCreationError::InvalidBounds => write!(f, "The minimum pool size can't exceed the maximum"),
+            PoolCreationError::InvalidCapacity => write!(f, "The queue capacity must be greater than 0"),
+            PoolCreationError::Spawn(err) => write!(f, "Failed to start a worker thread: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for PoolCreationError {}
+
+// Why a job was not accepted by the pool
+#[derive(Debug, PartialEq)]
+pub enum ExecuteError {
+    /// The queue was full and the job was not allowed to wait
+    Full,
+    /// The queue stayed full for the whole timeout
+    Timeout,
+    /// The pool is shutting down
+    ShutDown,
+}
+
+impl fmt::Display for ExecuteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExecuteError::Full => write!(f, "The job queue is full"),
+            ExecuteError::Timeout => write!(f, "Timed out waiting for room in the job queue"),
+            ExecuteError::ShutDown => write!(f, "The pool is shutting down"),
+        }
+    }
+}
+
+impl std::error::Error for ExecuteError {}
+
+/// Workers that were still running a job when `ThreadPool::shutdown` gave up.
+#[derive(Debug, PartialEq)]
+pub struct ShutdownTimeout {
+    pub timeout: Duration,
+    /// Ids of the workers left running, see `blog-worker-N` thread names
+    pub unfinished: Vec<u32>,
+}
+
+impl fmt::Display for ShutdownTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let ids: Vec<String> = self.unfinished.iter().map(u32::to_string).collect();
+        write!(
+            f,
+            "{} worker(s) still busy after {:?}: {}",
+            ids.len(),
+            self.timeout,
+            ids.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for ShutdownTimeout {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn submit_returns_results_in_submission_order() {
+        let pool = ThreadPool::new(4);
+        let handles: Vec<_> = (0..32u64)
+            .map(|n| {
+                pool.submit(move || {
+                    // Later jobs finish first, the handles keep their order anyway
+                    thread::sleep(Duration::from_millis(32 - n));
+                    n * n
+                })
+            })
+            .collect();
+
+        let results: Vec<u64> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+        assert_eq!(results, (0..32).map(|n| n * n).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn submit_propagates_panics_and_keeps_the_pool_alive() {
+        let pool = ThreadPool::new(1);
+        let failed = pool.submit(|| -> u32 { panic!("rendering failed") });
+
+        let payload = failed.join().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"rendering failed"));
+        assert_eq!(pool.panicked_jobs(), 1);
+
+        // The only worker survived the panic
+        assert_eq!(pool.submit(|| 7).join().unwrap(), 7);
+    }
+
+    #[test]
+    fn execute_panics_are_counted() {
+        let pool = ThreadPool::new(1);
+        pool.execute(|| panic!("boom"));
+        // A single worker runs jobs in order, so the panic has happened by now
+        let (done, finished) = mpsc::channel();
+        pool.execute(move || done.send(()).unwrap());
+        finished.recv().unwrap();
+        assert_eq!(pool.panicked_jobs(), 1);
+    }
+
+    #[test]
+    fn scope_jobs_borrow_from_the_stack() {
+        let pool = ThreadPool::new(3);
+        let words = ["static".to_string(), "site".to_string(), "generator".to_string()];
+        let mut total = 0;
+
+        let lengths: Vec<usize> = pool.scope(|scope| {
+            let handles: Vec<_> = words.iter().map(|word| scope.submit(|| word.len())).collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        pool.scope(|scope| {
+            scope.submit(|| total = words.len());
+        });
+
+        assert_eq!(lengths, [6, 4, 9]);
+        assert_eq!(total, 3);
+    }
+
+    #[test]
+    fn scope_waits_for_unjoined_jobs() {
+        let pool = ThreadPool::new(2);
+        let (sender, receiver) = mpsc::channel();
+
+        pool.scope(|scope| {
+            for n in 0..4 {
+                let sender = sender.clone();
+                scope.submit(move || {
+                    thread::sleep(Duration::from_millis(20));
+                    sender.send(n).unwrap();
+                });
+            }
+        });
+
+        let mut finished: Vec<i32> = receiver.try_iter().collect();
+        finished.sort();
+        assert_eq!(finished, [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn scope_hands_joined_panics_to_the_caller() {
+        let pool = ThreadPool::new(2);
+        let result = pool.scope(|scope| scope.submit(|| -> u32 { panic!("bad page") }).join());
+        assert!(result.is_err());
+        assert_eq!(pool.panicked_jobs(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "a job submitted to the scope panicked")]
+    fn scope_panics_when_a_panic_goes_unjoined() {
+        let pool = ThreadPool::new(2);
+        pool.scope(|scope| {
+            scope.submit(|| panic!("bad page"));
+        });
+    }
+
+    #[test]
+    fn try_execute_fails_fast_when_the_queue_is_full() {
+        let pool = ThreadPool::build_bounded(1, 1).unwrap();
+        let (release, blocked) = mpsc::channel::<()>();
+        let (started_sender, started) = mpsc::channel();
+
+        // Occupy the only worker, then fill the only queue slot
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        started.recv().unwrap();
+        pool.execute(|| {});
+
+        assert_eq!(pool.try_execute(|| {}), Err(ExecuteError::Full));
+        assert_eq!(
+            pool.execute_timeout(|| {}, Duration::from_millis(10)),
+            Err(ExecuteError::Timeout)
+        );
+
+        release.send(()).unwrap();
+        assert_eq!(pool.execute_timeout(|| {}, Duration::from_secs(5)), Ok(()));
+    }
+
+    #[test]
+    fn shutdown_reports_workers_stuck_in_a_job() {
+        let pool = ThreadPool::new(2);
+        let (started_sender, started) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        started.recv().unwrap();
+
+        let err = pool.shutdown(Duration::from_millis(50)).unwrap_err();
+        assert_eq!(err.unfinished.len(), 1);
+        drop(release);
+    }
+
+    #[test]
+    fn metrics_count_finished_and_running_jobs() {
+        let pool = ThreadPool::new(1);
+        for _ in 0..3 {
+            pool.submit(|| thread::sleep(Duration::from_millis(5))).join().unwrap();
+        }
+
+        // The worker records a job just after its result is sent
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.metrics().completed < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let metrics = pool.metrics();
+        assert_eq!(metrics.completed, 3);
+        assert_eq!(metrics.active, 0);
+        assert_eq!(metrics.queued, 0);
+        assert_eq!(metrics.workers, 1);
+        assert!(metrics.average_job_time >= Duration::from_millis(5));
+    }
+
+    #[test]
+    fn a_huge_worker_limit_starts_only_min_workers() {
+        let pool = ThreadPool::build_elastic(1, u32::MAX, 4).unwrap();
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.submit(|| 2 + 2).join().unwrap(), 4);
+    }
+
+    #[test]
+    fn an_elastic_pool_grows_under_load_and_shrinks_when_idle() {
+        let pool = ThreadPool::start(1, 4, 16, Duration::from_millis(50)).unwrap();
+        let (started, running) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Arc::new(Mutex::new(released));
+
+        // Four jobs that all block at once need four workers
+        for _ in 0..4 {
+            let started = started.clone();
+            let released = Arc::clone(&released);
+            pool.execute(move || {
+                started.send(()).unwrap();
+                let _ = released.lock().unwrap().recv();
+            });
+        }
+        for _ in 0..4 {
+            running.recv_timeout(Duration::from_secs(5)).expect("the pool didn't grow");
+        }
+        assert_eq!(pool.size(), 4);
+
+        drop(release);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.size() > 1 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
         }
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.submit(|| 2 + 2).join().unwrap(), 4);
     }
 }
@@ -0,0 +1,137 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::Job;
+
+// How long a push may wait for room in a full queue
+pub enum Wait {
+    Forever,
+    Never,
+    Until(Instant),
+}
+
+// Why a job was not queued
+pub enum PushError {
+    Full,
+    Timeout,
+    Closed,
+}
+
+// What a worker waiting for a job got
+pub enum Pop {
+    Job(Job),
+    Idle,
+    Closed,
+}
+
+/// A fixed capacity FIFO of jobs shared by the pool and its workers.
+pub struct JobQueue {
+    state: Mutex<State>,
+    capacity: usize,
+    // Signalled when a job is pushed or the queue is closed
+    not_empty: Condvar,
+    // Signalled when a job is popped
+    not_full: Condvar,
+}
+
+struct State {
+    jobs: VecDeque<Job>,
+    closed: bool,
+    // Workers currently waiting for a job
+    idle: usize,
+}
+
+impl JobQueue {
+    pub fn new(capacity: usize) -> JobQueue {
+        JobQueue {
+            state: Mutex::new(State {
+                jobs: VecDeque::with_capacity(capacity),
+                closed: false,
+                idle: 0,
+            }),
+            capacity,
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+        }
+    }
+
+    /// Queue `job`. Returns whether there are now more jobs waiting than
+    /// workers waiting for them.
+    pub fn push(&self, job: Job, wait: Wait) -> Result<bool, PushError> {
+        let mut state = self.lock();
+        loop {
+            if state.closed {
+                return Err(PushError::Closed);
+            }
+            if state.jobs.len() < self.capacity {
+                break;
+            }
+            state = match wait {
+                Wait::Never => return Err(PushError::Full),
+                Wait::Forever => self.not_full.wait(state).unwrap_or_else(PoisonError::into_inner),
+                Wait::Until(deadline) => {
+                    let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                        return Err(PushError::Timeout);
+                    };
+                    self.not_full
+                        .wait_timeout(state, timeout)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+            };
+        }
+
+        state.jobs.push_back(job);
+        let short_of_workers = state.jobs.len() > state.idle;
+        drop(state);
+        self.not_empty.notify_one();
+        Ok(short_of_workers)
+    }
+
+    /// The next job, waiting up to `idle_timeout` for one. Once the queue is
+    /// closed, jobs queued before that are still handed out first.
+    pub fn pop(&self, idle_timeout: Duration) -> Pop {
+        let deadline = Instant::now() + idle_timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(job) = state.jobs.pop_front() {
+                drop(state);
+                self.not_full.notify_one();
+                return Pop::Job(job);
+            }
+            if state.closed {
+                return Pop::Closed;
+            }
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                return Pop::Idle;
+            };
+
+            state.idle += 1;
+            state = self
+                .not_empty
+                .wait_timeout(state, timeout)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+            state.idle -= 1;
+        }
+    }
+
+    /// Jobs waiting for a worker.
+    pub fn len(&self) -> usize {
+        self.lock().jobs.len()
+    }
+
+    /// Refuse new jobs and wake everyone waiting on the queue.
+    pub fn close(&self) {
+        self.lock().closed = true;
+        self.not_empty.notify_all();
+        self.not_full.notify_all();
+    }
+
+    // Jobs never run while the lock is held, so a poisoned lock still
+    // guards a consistent queue
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
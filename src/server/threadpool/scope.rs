@@ -0,0 +1,127 @@
+use std::marker::PhantomData;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Condvar, Mutex, PoisonError,
+};
+use std::thread;
+
+use super::{handle::JobHandle, Job, ThreadPool};
+
+/// Jobs submitted through a scope may borrow anything that outlives it,
+/// see `ThreadPool::scope`.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'env ThreadPool,
+    state: Arc<ScopeState>,
+    // Same variance as std::thread::Scope, 'scope must stay exactly 'scope
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+// Shared between a scope and its jobs
+pub(super) struct ScopeState {
+    pending: Mutex<usize>,
+    all_done: Condvar,
+    // A job panicked and nobody joined its handle to find out
+    unobserved_panic: AtomicBool,
+}
+
+/// The result of a job submitted through a `Scope`.
+pub struct ScopedJobHandle<'scope, T> {
+    handle: Option<JobHandle<T>>,
+    state: Arc<ScopeState>,
+    scope: PhantomData<&'scope ()>,
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    pub(super) fn new(pool: &'env ThreadPool) -> Scope<'scope, 'env> {
+        Scope {
+            pool,
+            state: Arc::new(ScopeState {
+                pending: Mutex::new(0),
+                all_done: Condvar::new(),
+                unobserved_panic: AtomicBool::new(false),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        }
+    }
+
+    /// Queue `f` on the pool, blocking while the queue is full.
+    pub fn submit<F, T>(&'scope self, f: F) -> ScopedJobHandle<'scope, T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let (sender, handle) = JobHandle::new();
+        let shared = Arc::clone(&self.pool.shared);
+        let state = Arc::clone(&self.state);
+        *state.lock_pending() += 1;
+
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            let result = super::run_job(&shared, f);
+            let panicked = result.is_err();
+            // A failed send means the handle is gone, so this panic would go unseen
+            if sender.send(result).is_err() && panicked {
+                state.unobserved_panic.store(true, Ordering::SeqCst);
+            }
+            // Nothing borrowed from the scope may be touched once it is told
+            // this job is done
+            drop(sender);
+            state.finish_one();
+        });
+
+        // SAFETY: `ThreadPool::scope` doesn't return, or unwind, before every
+        // job submitted through the scope has finished and been dropped, so
+        // the borrows the job holds never outlive what they borrow
+        let job: Job = unsafe { std::mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        self.pool.push_job(job);
+
+        ScopedJobHandle {
+            handle: Some(handle),
+            state: Arc::clone(&self.state),
+            scope: PhantomData,
+        }
+    }
+
+    // Block until every job submitted through the scope has finished
+    pub(super) fn wait(&self) {
+        let mut pending = self.state.lock_pending();
+        while *pending > 0 {
+            pending = self.state.all_done.wait(pending).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    pub(super) fn had_unobserved_panic(&self) -> bool {
+        self.state.unobserved_panic.load(Ordering::SeqCst)
+    }
+}
+
+impl ScopeState {
+    fn lock_pending(&self) -> std::sync::MutexGuard<'_, usize> {
+        self.pending.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn finish_one(&self) {
+        let mut pending = self.lock_pending();
+        *pending -= 1;
+        if *pending == 0 {
+            self.all_done.notify_all();
+        }
+    }
+}
+
+impl<T> ScopedJobHandle<'_, T> {
+    /// Wait for the job to finish, see `JobHandle::join`.
+    pub fn join(mut self) -> thread::Result<T> {
+        self.handle.take().expect("handle is only taken by join").join()
+    }
+}
+
+impl<T> Drop for ScopedJobHandle<'_, T> {
+    fn drop(&mut self) {
+        // Dropped without a join after the job already panicked
+        if let Some(Err(_)) = self.handle.as_ref().and_then(JobHandle::try_result) {
+            self.state.unobserved_panic.store(true, Ordering::SeqCst);
+        }
+    }
+}
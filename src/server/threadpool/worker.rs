@@ -1,33 +1,97 @@
-use std::sync::{mpsc, Arc, Mutex};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{atomic::Ordering, Arc};
 use std::thread;
+use std::time::Instant;
+
+use super::queue::Pop;
+use super::Shared;
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
 pub struct Worker {
     pub id: u32,
     pub thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    pub fn new(id: u32, receiver: Arc<Mutex<mpsc::Receiver<Job>>>) -> Worker {
-        let thread = thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv();
+    pub fn new(id: u32, shared: Arc<Shared>) -> io::Result<Worker> {
+        // Named so the threads can be told apart in debuggers and panics
+        let thread = thread::Builder::new()
+            .name(format!("blog-worker-{id}"))
+            .spawn(move || run(id, shared))?;
+
+        Ok(Worker {
+            id,
+            thread: Some(thread),
+        })
+    }
+}
+
+fn run(id: u32, shared: Arc<Shared>) {
+    // Replaces this worker if the thread dies anyway, e.g. when printing
+    // fails. Jobs themselves can't take it down, their panics are caught.
+    let _sentinel = Sentinel {
+        id,
+        shared: Arc::clone(&shared),
+    };
 
-            match message {
-                Ok(job) => {
-                    // println!("Worker {id} got a job; executing.");
+    loop {
+        match shared.queue.pop(shared.idle_timeout) {
+            Pop::Job(job) => {
+                // println!("Worker {id} got a job; executing.");
+                shared.counters.job_started();
+                let start = Instant::now();
 
-                    job();
+                // The panic hook has already printed the message, and the
+                // payload is only dropped after the panic is counted
+                if let Err(_payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+                    shared.panicked_jobs.fetch_add(1, Ordering::SeqCst);
+                    eprintln!("Worker {id} recovered from a panicking job");
                 }
-                Err(_) => {
-                    println!("Worker {id} disconnected; shutting down.");
+                shared.counters.job_finished(start.elapsed());
+            }
+            Pop::Idle => {
+                // Only workers the pool grew beyond its minimum go away
+                if shared.retire_idle_worker() {
+                    shared.lock_workers().retain(|worker| worker.id != id);
                     break;
                 }
             }
-        });
+            Pop::Closed => {
+                println!("Worker {id} disconnected; shutting down.");
+                break;
+            }
+        }
+    }
+}
+
+// Starts a replacement when its worker thread unwinds
+struct Sentinel {
+    id: u32,
+    shared: Arc<Shared>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        // A worker that dies while the pool shuts down would only exit again
+        if !thread::panicking() || self.shared.shutting_down.load(Ordering::SeqCst) {
+            return;
+        }
+
+        eprintln!("Worker {} died, starting a replacement", self.id);
+        let replacement = match Worker::new(self.id, Arc::clone(&self.shared)) {
+            Ok(replacement) => replacement,
+            Err(err) => {
+                eprintln!("Failed to replace worker {}: {}", self.id, err);
+                self.shared.live_workers.fetch_sub(1, Ordering::SeqCst);
+                return;
+            }
+        };
 
-        Worker {
-            id,
-            thread: Some(thread),
+        // The pool joins whatever is registered here when it shuts down
+        let mut workers = self.shared.lock_workers();
+        match workers.iter_mut().find(|worker| worker.id == self.id) {
+            Some(worker) => *worker = replacement,
+            None => workers.push(replacement),
         }
     }
 }
@@ -0,0 +1,80 @@
+use std::{error::Error, fs, path::Path, sync::Arc};
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    ServerConfig, ServerConnection,
+};
+
+// Names the generated certificate is valid for
+const LOCAL_NAMES: [&str; 3] = ["localhost", "127.0.0.1", "::1"];
+
+// Generated certificate and key, inside the cache directory
+const CERT_FILE: &str = "localhost.pem";
+const KEY_FILE: &str = "localhost-key.pem";
+
+// First byte of a TLS record carrying a handshake, which is how every TLS
+// connection starts. Plain HTTP starts with a method name instead.
+const TLS_HANDSHAKE: u8 = 0x16;
+
+/// Certificate and private key the server uses for HTTPS.
+#[derive(Clone)]
+pub struct TlsConfig(Arc<ServerConfig>);
+
+impl TlsConfig {
+    /// Load a PEM certificate chain and the PEM private key that goes with it.
+    pub fn from_pem_files(cert_path: &Path, key_path: &Path) -> Result<TlsConfig, Box<dyn Error>> {
+        let certs = CertificateDer::pem_file_iter(cert_path)
+            .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+            .map_err(|err| format!("Invalid certificate {}: {}", cert_path.display(), err))?;
+        if certs.is_empty() {
+            return Err(format!("No certificate found in {}", cert_path.display()).into());
+        }
+        let key = PrivateKeyDer::from_pem_file(key_path)
+            .map_err(|err| format!("Invalid private key {}: {}", key_path.display(), err))?;
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsConfig(Arc::new(config)))
+    }
+
+    /// A self-signed certificate for localhost, generated on first use and
+    /// reused from `cache_dir` afterwards so the browser exception sticks.
+    pub fn self_signed(cache_dir: &Path) -> Result<TlsConfig, Box<dyn Error>> {
+        let cert_path = cache_dir.join(CERT_FILE);
+        let key_path = cache_dir.join(KEY_FILE);
+
+        if !cert_path.is_file() || !key_path.is_file() {
+            let names = LOCAL_NAMES.map(String::from).to_vec();
+            let certified = rcgen::generate_simple_self_signed(names)?;
+            fs::create_dir_all(cache_dir)?;
+            fs::write(&cert_path, certified.cert.pem())?;
+            write_private(&key_path, certified.key_pair.serialize_pem().as_bytes())?;
+            println!("Generated a self-signed certificate in {}", cert_path.display());
+        }
+
+        TlsConfig::from_pem_files(&cert_path, &key_path)
+    }
+
+    pub(super) fn accept(&self) -> Result<ServerConnection, rustls::Error> {
+        ServerConnection::new(Arc::clone(&self.0))
+    }
+}
+
+/// Whether a connection starting with `first_byte` is a TLS handshake.
+pub(super) fn is_handshake(first_byte: u8) -> bool {
+    first_byte == TLS_HANDSHAKE
+}
+
+// The key is only readable by its owner where the platform supports it
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    fs::write(path, contents)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(())
+}